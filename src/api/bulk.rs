@@ -0,0 +1,419 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use axum::{extract::Path, routing::post, Router};
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use utoipa::ToSchema;
+
+use super::{
+    ctx::Ctx,
+    extract::Json,
+    member::{
+        apply_member_update, fetch_member, list_members, remove_member, MemberFilter, MemberPayload,
+    },
+    ApiError, Result, SharedState,
+};
+
+const DEFAULT_CONCURRENCY: usize = 8;
+const MAX_CONCURRENCY: usize = 64;
+
+#[inline]
+pub fn routes() -> Router<SharedState> {
+    Router::new().route("/network/:network_id/member/bulk", post(bulk_members))
+}
+
+/// Applies an operation to many members, the results are in the order of
+/// `members`, or of the node ids for a filter. Node ids are 10 hex digits, so
+/// `bulk` is never taken for one by `/network/{network_id}/member/{member_id}`.
+#[utoipa::path(
+    post,
+    path = "/network/{network_id}/member/bulk",
+    tag = "member",
+    params(("network_id" = String, Path, description = "16 hex digits network id")),
+    request_body = BulkRequest,
+    responses((status = 200, body = BulkResponse), (status = 400)),
+)]
+async fn bulk_members(
+    ctx: Ctx,
+    Path(network_id): Path<String>,
+    Json(request): Json<BulkRequest>,
+) -> Result<Json<BulkResponse>> {
+    if request.members.is_none() && request.filter.is_none() {
+        return Err(ApiError::invalid(
+            "members",
+            "either `members` or `filter` is required",
+        ));
+    }
+
+    if let BulkOperation::MoveIp { from, to } = &request.operation {
+        let (from, to) = match (parse_cidr("from", from), parse_cidr("to", to)) {
+            (Ok(from), Ok(to)) => (from, to),
+            (from, to) => {
+                let fields = [from.err(), to.err()]
+                    .into_iter()
+                    .flat_map(|err| match err {
+                        Some(ApiError::InvalidFields(fields)) => fields,
+                        _ => vec![],
+                    })
+                    .collect();
+                return Err(ApiError::InvalidFields(fields));
+            }
+        };
+        if from.0.is_ipv4() != to.0.is_ipv4() {
+            return Err(ApiError::invalid(
+                "to",
+                "must be of the same address family as `from`",
+            ));
+        }
+    }
+
+    let concurrency = request
+        .concurrency
+        .unwrap_or(DEFAULT_CONCURRENCY)
+        .clamp(1, MAX_CONCURRENCY);
+
+    let filter = request.filter.unwrap_or_default();
+    let operation = &request.operation;
+    let network_id = network_id.as_str();
+    let ctx = &ctx;
+
+    let targets = match request.members {
+        // only the given members are fetched.
+        Some(mut member_ids) => {
+            let mut seen = HashSet::new();
+            member_ids.retain(|id| seen.insert(id.clone()));
+            stream::iter(member_ids)
+                .map(|member_id| async move {
+                    let member = fetch_member(ctx, network_id, &member_id).await;
+                    (member_id, member)
+                })
+                .buffered(concurrency)
+                .filter(|(_, member)| {
+                    let matched = member.as_ref().map_or(true, |m| filter.matches(m));
+                    async move { matched }
+                })
+                .collect::<Vec<_>>()
+                .await
+        }
+        None => {
+            let mut targets = list_members(ctx, network_id)
+                .await?
+                .into_iter()
+                .filter(|m| filter.matches(m))
+                .filter_map(|m| m.node_id.clone().map(|id| (id, Ok(m))))
+                .collect::<Vec<_>>();
+            targets.sort_by(|(a, _), (b, _)| a.cmp(b));
+            targets
+        }
+    };
+
+    let results = stream::iter(targets.into_iter().enumerate())
+        .map(|(index, (member_id, member))| async move {
+            let result = match member {
+                Ok(member) => operation.apply(ctx, network_id, member, index).await,
+                Err(err) => Err(err),
+            };
+            BulkResult::new(member_id, result)
+        })
+        .buffered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+
+    let failed = results.iter().filter(|r| !r.ok).count();
+
+    Ok(Json(BulkResponse {
+        succeeded: results.len() - failed,
+        failed,
+        results,
+    }))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct BulkRequest {
+    /// explicit member ids to operate on.
+    members: Option<Vec<String>>,
+    /// select members by criteria, combined with `members` when both are given.
+    filter: Option<MemberFilter>,
+    /// maximum number of members processed in parallel.
+    concurrency: Option<usize>,
+    #[serde(flatten)]
+    operation: BulkOperation,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(tag = "op", rename_all = "camelCase")]
+enum BulkOperation {
+    Authorize,
+    Deauthorize,
+    Delete,
+    /// set the given `[id, value]` tags, keeping the other tags of the member.
+    SetTags {
+        tags: Vec<(u32, u32)>,
+    },
+    /// `{nodeId}` and `{index}` (1-based) are substituted in the name.
+    SetName {
+        name: String,
+    },
+    /// renumber assigned addresses inside `from` to the same host in `to`.
+    MoveIp {
+        from: String,
+        to: String,
+    },
+}
+
+impl BulkOperation {
+    async fn apply(
+        &self,
+        ctx: &Ctx,
+        network_id: &str,
+        member: MemberPayload,
+        index: usize,
+    ) -> Result<Option<MemberPayload>> {
+        let member_id = member.node_id.clone().unwrap_or_default();
+        let config = member.config.unwrap_or_default();
+
+        let update = match self {
+            BulkOperation::Delete => {
+                return remove_member(ctx, network_id, &member_id).await.map(Some)
+            }
+            BulkOperation::Authorize | BulkOperation::Deauthorize => {
+                let authorized = matches!(self, BulkOperation::Authorize);
+                MemberPayload {
+                    config: Some(partial_config("authorized", json!(authorized))),
+                    ..Default::default()
+                }
+            }
+            BulkOperation::SetTags { tags } => {
+                let mut merged = config
+                    .get("tags")
+                    .and_then(|t| serde_json::from_value::<Vec<(u32, u32)>>(t.clone()).ok())
+                    .unwrap_or_default();
+                for (id, value) in tags {
+                    match merged.iter_mut().find(|(i, _)| i == id) {
+                        Some(tag) => tag.1 = *value,
+                        None => merged.push((*id, *value)),
+                    }
+                }
+                MemberPayload {
+                    config: Some(partial_config("tags", json!(merged))),
+                    ..Default::default()
+                }
+            }
+            BulkOperation::SetName { name } => MemberPayload {
+                name: Some(
+                    name.replace("{nodeId}", &member_id)
+                        .replace("{index}", &(index + 1).to_string()),
+                ),
+                ..Default::default()
+            },
+            BulkOperation::MoveIp { from, to } => {
+                let (from, to) = (parse_cidr("from", from)?, parse_cidr("to", to)?);
+                let ips = config
+                    .get("ipAssignments")
+                    .and_then(|x| x.as_array())
+                    .cloned()
+                    .unwrap_or_default();
+
+                let mut changed = false;
+                let mut moved = Vec::with_capacity(ips.len());
+                for ip in ips {
+                    match ip.as_str().and_then(|s| s.parse::<IpAddr>().ok()) {
+                        Some(addr) => match move_ip(addr, from, to)? {
+                            Some(addr) => {
+                                changed = true;
+                                moved.push(json!(addr.to_string()));
+                            }
+                            None => moved.push(ip),
+                        },
+                        None => moved.push(ip),
+                    }
+                }
+
+                if !changed {
+                    return Ok(None);
+                }
+
+                MemberPayload {
+                    config: Some(partial_config("ipAssignments", json!(moved))),
+                    ..Default::default()
+                }
+            }
+        };
+
+        apply_member_update(ctx, network_id, &member_id, update)
+            .await
+            .map(Some)
+    }
+}
+
+fn partial_config(key: &str, value: Value) -> Map<String, Value> {
+    let mut config = Map::new();
+    config.insert(key.to_string(), value);
+    config
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct BulkResponse {
+    succeeded: usize,
+    failed: usize,
+    results: Vec<BulkResult>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct BulkResult {
+    member_id: String,
+    ok: bool,
+    /// `false` when the operation did not apply to this member.
+    changed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    member: Option<MemberPayload>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<BulkError>,
+}
+
+/// Why the operation failed for a member, as in error responses.
+#[derive(Debug, Serialize, ToSchema)]
+struct BulkError {
+    /// e.g. `member_not_found` or `controller_unreachable`.
+    code: &'static str,
+    message: String,
+}
+
+impl BulkResult {
+    fn new(member_id: String, result: Result<Option<MemberPayload>>) -> Self {
+        match result {
+            Ok(member) => Self {
+                member_id,
+                ok: true,
+                changed: member.is_some(),
+                member,
+                error: None,
+            },
+            Err(err) => Self {
+                member_id,
+                ok: false,
+                changed: false,
+                member: None,
+                error: Some(BulkError {
+                    code: err.code(),
+                    message: err.to_string(),
+                }),
+            },
+        }
+    }
+}
+
+pub(super) type Cidr = (IpAddr, u8);
+
+pub(super) fn parse_cidr(field: &str, s: &str) -> Result<Cidr> {
+    let invalid = || ApiError::invalid(field, format!("`{s}` is not a cidr"));
+    let (addr, prefix) = s.split_once('/').ok_or_else(invalid)?;
+    let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+    let prefix = prefix.parse::<u8>().map_err(|_| invalid())?;
+    if prefix > addr_bits(&addr) {
+        return Err(invalid());
+    }
+    Ok((addr, prefix))
+}
+
+/// Returns the address with the host part of `addr` kept and the network
+/// part replaced by `to`, or `None` if `addr` is outside of `from`.
+fn move_ip(addr: IpAddr, from: Cidr, to: Cidr) -> Result<Option<IpAddr>> {
+    if addr.is_ipv4() != from.0.is_ipv4() || !contains(from, addr) {
+        return Ok(None);
+    }
+
+    let bits = addr_bits(&addr);
+    let host = addr_to_u128(addr) & !mask(bits, from.1);
+    if host & mask(bits, to.1) != 0 {
+        return Err(ApiError::BadRequest(format!(
+            "{addr} does not fit into {}/{}",
+            to.0, to.1
+        )));
+    }
+
+    let moved = (addr_to_u128(to.0) & mask(bits, to.1)) | host;
+    Ok(Some(match addr {
+        IpAddr::V4(_) => Ipv4Addr::from(moved as u32).into(),
+        IpAddr::V6(_) => Ipv6Addr::from(moved).into(),
+    }))
+}
+
+fn contains((net, prefix): Cidr, addr: IpAddr) -> bool {
+    let mask = mask(addr_bits(&addr), prefix);
+    addr_to_u128(net) & mask == addr_to_u128(addr) & mask
+}
+
+pub(super) fn mask(bits: u8, prefix: u8) -> u128 {
+    let all = if bits == 128 {
+        u128::MAX
+    } else {
+        (1u128 << bits) - 1
+    };
+    let host = if prefix == 0 {
+        all
+    } else {
+        (1u128 << (bits - prefix)) - 1
+    };
+    all & !host
+}
+
+pub(super) fn addr_bits(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+pub(super) fn addr_to_u128(addr: IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(v4) => u32::from(v4) as u128,
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_move_ip() {
+        let from = parse_cidr("from", "10.1.0.0/16").unwrap();
+        let to = parse_cidr("to", "10.2.0.0/16").unwrap();
+
+        let moved = move_ip("10.1.3.4".parse().unwrap(), from, to).unwrap();
+        assert_eq!(moved, Some("10.2.3.4".parse().unwrap()));
+
+        let outside = move_ip("192.168.3.4".parse().unwrap(), from, to).unwrap();
+        assert_eq!(outside, None);
+
+        let narrow = parse_cidr("to", "10.3.0.0/24").unwrap();
+        assert!(move_ip("10.1.3.4".parse().unwrap(), from, narrow).is_err());
+
+        let from = parse_cidr("from", "fd00:1::/64").unwrap();
+        let to = parse_cidr("to", "fd00:2::/64").unwrap();
+        let moved = move_ip("fd00:1::42".parse().unwrap(), from, to).unwrap();
+        assert_eq!(moved, Some("fd00:2::42".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_bulk_request() {
+        let request = serde_json::from_value::<BulkRequest>(json!({
+            "op": "setTags",
+            "tags": [[1, 2]],
+            "filter": { "authorized": false }
+        }))
+        .unwrap();
+
+        assert!(matches!(request.operation, BulkOperation::SetTags { .. }));
+        assert_eq!(request.filter.unwrap().authorized, Some(false));
+        assert!(request.members.is_none());
+    }
+}
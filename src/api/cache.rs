@@ -0,0 +1,328 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::{stream, StreamExt, TryStreamExt};
+use serde_json::{Map, Value};
+use tokio::sync::Notify;
+
+use super::{ctx::Ctx, ApiError, ControllerState, Result, SharedState};
+use crate::log;
+
+/// In-memory copy of the controller state, shared by all requests.
+///
+/// A snapshot is only served to requests carrying the token it was fetched
+/// with, so the cache never bypasses the controller's authentication.
+#[derive(Debug)]
+pub struct Cache {
+    interval: Duration,
+    token: RwLock<Option<String>>,
+    snapshot: RwLock<Option<Snapshot>>,
+    token_changed: Notify,
+    /// bumped by every write, a snapshot fetched before a write is outdated.
+    generation: AtomicU64,
+    /// whether a member is authorized, by network, member and revision.
+    authorized: RwLock<HashMap<(String, String), (u64, bool)>>,
+}
+
+/// Requests in flight of a fan-out over networks or members.
+pub(super) const FETCH_CONCURRENCY: usize = 8;
+
+#[derive(Debug)]
+pub struct Snapshot {
+    token: String,
+    refreshed_at: Instant,
+    pub network_ids: Vec<String>,
+    pub networks: HashMap<String, Map<String, Value>>,
+    pub members: HashMap<String, BTreeMap<String, Map<String, Value>>>,
+    pub peers: BTreeMap<String, Map<String, Value>>,
+}
+
+impl Cache {
+    /// A zero `interval` disables the cache.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            token: Default::default(),
+            snapshot: Default::default(),
+            token_changed: Notify::new(),
+            generation: AtomicU64::new(0),
+            authorized: Default::default(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.interval.is_zero()
+    }
+
+    /// Looks up the snapshot, `None` if it is missing, stale or was fetched
+    /// with another token.
+    pub fn get<T>(&self, token: Option<&str>, f: impl FnOnce(&Snapshot) -> T) -> Option<T> {
+        let snapshot = self.snapshot.read().ok()?;
+        let snapshot = snapshot.as_ref()?;
+        if Some(snapshot.token.as_str()) != token
+            || snapshot.refreshed_at.elapsed() > self.interval * 2
+        {
+            return None;
+        }
+        Some(f(snapshot))
+    }
+
+    /// Remembers a token the controller accepted, for background tasks.
+    pub fn accept_token(&self, token: &str) {
+        if token.is_empty() {
+            return;
+        }
+        if self.token.read().is_ok_and(|t| t.as_deref() == Some(token)) {
+            return;
+        }
+        if let Ok(mut t) = self.token.write() {
+            *t = Some(token.to_string());
+            self.token_changed.notify_one();
+        }
+    }
+
+    pub fn put_network(&self, network_id: &str, network: Map<String, Value>) {
+        self.update(|s| {
+            if !s.networks.contains_key(network_id) {
+                s.network_ids.push(network_id.to_string());
+                s.members.entry(network_id.to_string()).or_default();
+            }
+            s.networks.insert(network_id.to_string(), network);
+        })
+    }
+
+    pub fn remove_network(&self, network_id: &str) {
+        self.update(|s| {
+            s.network_ids.retain(|id| id != network_id);
+            s.networks.remove(network_id);
+            s.members.remove(network_id);
+        })
+    }
+
+    pub fn put_member(&self, network_id: &str, member_id: &str, member: Map<String, Value>) {
+        self.update(|s| {
+            if let Some(members) = s.members.get_mut(network_id) {
+                members.insert(member_id.to_string(), member);
+            }
+        })
+    }
+
+    pub fn remove_member(&self, network_id: &str, member_id: &str) {
+        self.update(|s| {
+            if let Some(members) = s.members.get_mut(network_id) {
+                members.remove(member_id);
+            }
+        })
+    }
+
+    /// Whether a member of `revision` is authorized, if known.
+    pub fn authorized(&self, network_id: &str, member_id: &str, revision: u64) -> Option<bool> {
+        let authorized = self.authorized.read().ok()?;
+        match authorized.get(&(network_id.to_string(), member_id.to_string())) {
+            Some((r, authorized)) if *r == revision => Some(*authorized),
+            _ => None,
+        }
+    }
+
+    pub fn put_authorized(
+        &self,
+        network_id: &str,
+        member_id: &str,
+        revision: u64,
+        authorized: bool,
+    ) {
+        if let Ok(mut map) = self.authorized.write() {
+            map.insert(
+                (network_id.to_string(), member_id.to_string()),
+                (revision, authorized),
+            );
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Snapshot)) {
+        if let Ok(mut snapshot) = self.snapshot.write() {
+            self.generation.fetch_add(1, Ordering::SeqCst);
+            if let Some(snapshot) = snapshot.as_mut() {
+                f(snapshot);
+            }
+        }
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    fn replace(&self, snapshot: Option<Snapshot>) {
+        if let Ok(mut s) = self.snapshot.write() {
+            *s = snapshot;
+        }
+    }
+
+    /// Replaces the snapshot unless a write happened since `generation`, the
+    /// snapshot would revert it. Returns whether it was replaced.
+    fn replace_if_current(&self, snapshot: Snapshot, generation: u64) -> bool {
+        let Ok(mut s) = self.snapshot.write() else {
+            return false;
+        };
+        if self.generation() != generation {
+            return false;
+        }
+        *s = Some(snapshot);
+        true
+    }
+
+    pub fn token(&self) -> Option<String> {
+        self.token.read().ok().and_then(|t| t.clone())
+    }
+}
+
+impl Snapshot {
+    pub fn member_ids(&self, network_id: &str) -> Option<Map<String, Value>> {
+        let members = self.members.get(network_id)?;
+        Some(
+            members
+                .iter()
+                .map(|(id, member)| {
+                    let revision = member.get("revision").cloned().unwrap_or_default();
+                    (id.clone(), revision)
+                })
+                .collect(),
+        )
+    }
+
+    pub fn member(&self, network_id: &str, member_id: &str) -> Option<&Map<String, Value>> {
+        self.members.get(network_id)?.get(member_id)
+    }
+
+    async fn fetch(ctx: &Ctx) -> Result<Self> {
+        let network_ids = ctx.get_network_ids().await?;
+
+        let networks = stream::iter(network_ids.clone())
+            .map(|network_id| async move {
+                let network = ctx.get_network(&network_id).await?;
+                let member_ids = ctx.get_member_ids(&network_id).await?;
+                let network_id = &network_id;
+                let members = stream::iter(member_ids.into_iter().map(|(id, _)| id))
+                    .map(|member_id| async move {
+                        let member = ctx.get_member(network_id, &member_id).await?;
+                        Ok::<_, ApiError>((member_id, member))
+                    })
+                    .buffer_unordered(FETCH_CONCURRENCY)
+                    .try_collect::<BTreeMap<_, _>>()
+                    .await?;
+                Ok::<_, ApiError>((network_id.clone(), network, members))
+            })
+            .buffer_unordered(FETCH_CONCURRENCY)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        let peers = ctx
+            .get_peers()
+            .await?
+            .into_iter()
+            .filter_map(|peer| {
+                let address = peer.get("address")?.as_str()?.to_string();
+                Some((address, peer))
+            })
+            .collect();
+
+        let mut snapshot = Snapshot {
+            token: ctx.zt1_token().unwrap_or_default().to_string(),
+            refreshed_at: Instant::now(),
+            network_ids,
+            networks: Default::default(),
+            members: Default::default(),
+            peers,
+        };
+
+        for (network_id, network, members) in networks {
+            snapshot.networks.insert(network_id.clone(), network);
+            snapshot.members.insert(network_id, members);
+        }
+
+        Ok(snapshot)
+    }
+}
+
+/// Keeps the cache fresh using the last token accepted by the controller.
+pub fn spawn_refresh(state: SharedState, controller: Arc<ControllerState>) {
+    if !controller.cache.enabled() {
+        return;
+    }
+
+    state.tasks.clone().spawn(async move {
+        let cache = &controller.cache;
+        let name = &controller.name;
+        loop {
+            if let Some(ctx) = Ctx::background_on(&state, &controller) {
+                let ctx = ctx.without_cache();
+                let generation = cache.generation();
+                match Snapshot::fetch(&ctx).await {
+                    Ok(snapshot) => {
+                        if !cache.replace_if_current(snapshot, generation) {
+                            log::debug!("cache refresh of {} discarded, written meanwhile.", name);
+                        }
+                    }
+                    Err(ApiError::Unauthorized) => {
+                        log::warn!(
+                            "cache refresh of {} rejected by controller, token discarded.",
+                            name
+                        );
+                        if let Ok(mut token) = cache.token.write() {
+                            *token = None;
+                        }
+                        cache.replace(None);
+                    }
+                    Err(err) => log::warn!("cache refresh of {} failed: {}", name, err),
+                }
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(cache.interval) => (),
+                _ = cache.token_changed.notified() => (),
+                _ = state.shutdown.cancelled() => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            token: Default::default(),
+            refreshed_at: Instant::now(),
+            network_ids: Default::default(),
+            networks: Default::default(),
+            members: Default::default(),
+            peers: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_replace_if_current() {
+        let cache = Cache::new(Duration::from_secs(60));
+
+        let generation = cache.generation();
+        assert!(cache.replace_if_current(snapshot(), generation));
+
+        // a write landing while the next snapshot is fetched
+        let generation = cache.generation();
+        cache.put_network("8056c2e21c000001", Map::new());
+        assert!(!cache.replace_if_current(snapshot(), generation));
+        let kept = cache.snapshot.read().unwrap();
+        assert!(kept
+            .as_ref()
+            .unwrap()
+            .networks
+            .contains_key("8056c2e21c000001"));
+    }
+}
@@ -0,0 +1,185 @@
+//! Subset of the ZeroTier Central API (`https://api.zerotier.com/api/v1`), so
+//! that Central clients such as the Terraform provider can manage this
+//! controller. Tokens are sent as `Authorization: Bearer <token>`.
+
+use axum::{
+    extract::Path,
+    routing::{delete, get, post},
+    Router,
+};
+use serde_json::{json, Map, Value};
+
+use super::{
+    ctx::Ctx,
+    extract::Json,
+    history::now_millis,
+    member::{self, MemberPayload},
+    network::{self, NetworkPalyload},
+    ApiError, Result, SharedState,
+};
+
+#[inline]
+pub fn routes() -> Router<SharedState> {
+    Router::new()
+        .route("/status", get(get_status))
+        .route("/network", get(get_networks))
+        .route("/network", post(create_network))
+        .route("/network/:network_id", get(get_network))
+        .route("/network/:network_id", post(update_network))
+        .route("/network/:network_id", delete(delete_network))
+        .route("/network/:network_id/member", get(get_members))
+        .route("/network/:network_id/member/:member_id", get(get_member))
+        .route(
+            "/network/:network_id/member/:member_id",
+            post(update_member),
+        )
+        .route(
+            "/network/:network_id/member/:member_id",
+            delete(delete_member),
+        )
+}
+
+async fn get_status(ctx: Ctx) -> Result<Json<Value>> {
+    let status = ctx.get_status().await?;
+    Ok(Json(json!({
+        "id": status.get("address"),
+        "type": "CentralStatus",
+        "clock": now_millis(),
+        "version": status.get("version"),
+        "apiVersion": "4",
+        "online": status.get("online"),
+        "readOnlyMode": false,
+    })))
+}
+
+async fn get_networks(ctx: Ctx) -> Result<Json<Vec<NetworkPalyload>>> {
+    Ok(Json(network::list_networks(&ctx).await?))
+}
+
+async fn create_network(
+    ctx: Ctx,
+    Json(network): Json<NetworkPalyload>,
+) -> Result<Json<NetworkPalyload>> {
+    Ok(Json(network::add_network(&ctx, network).await?))
+}
+
+async fn get_network(ctx: Ctx, Path(network_id): Path<String>) -> Result<Json<NetworkPalyload>> {
+    Ok(Json(network::fetch_network(&ctx, &network_id).await?))
+}
+
+async fn update_network(
+    ctx: Ctx,
+    Path(network_id): Path<String>,
+    Json(network): Json<NetworkPalyload>,
+) -> Result<Json<NetworkPalyload>> {
+    let _guard = ctx.lock_updates().await;
+    Ok(Json(
+        network::apply_network_update(&ctx, &network_id, network).await?,
+    ))
+}
+
+async fn delete_network(ctx: Ctx, Path(network_id): Path<String>) -> Result<Json<NetworkPalyload>> {
+    Ok(Json(network::remove_network(&ctx, &network_id).await?))
+}
+
+async fn get_members(ctx: Ctx, Path(network_id): Path<String>) -> Result<Json<Vec<Value>>> {
+    let members = member::list_members(&ctx, &network_id).await?;
+    Ok(Json(
+        members
+            .into_iter()
+            .map(|m| to_central_member(&network_id, m))
+            .collect::<Result<_>>()?,
+    ))
+}
+
+async fn get_member(
+    ctx: Ctx,
+    Path((network_id, member_id)): Path<(String, String)>,
+) -> Result<Json<Value>> {
+    let member = member::fetch_member(&ctx, &network_id, &member_id).await?;
+    Ok(Json(to_central_member(&network_id, member)?))
+}
+
+/// Like Central, creates the member when it has not joined yet, e.g. to
+/// authorize it in advance.
+async fn update_member(
+    ctx: Ctx,
+    Path((network_id, member_id)): Path<(String, String)>,
+    Json(member): Json<MemberPayload>,
+) -> Result<Json<Value>> {
+    let _guard = ctx.lock_updates().await;
+    match ctx.get_member(&network_id, &member_id).await {
+        Err(ApiError::MemberNotFound(_)) => {
+            ctx.update_member(&network_id, &member_id, &Map::new())
+                .await?;
+        }
+        result => {
+            result?;
+        }
+    }
+    let member = member::apply_member_update(&ctx, &network_id, &member_id, member).await?;
+    Ok(Json(to_central_member(&network_id, member)?))
+}
+
+async fn delete_member(
+    ctx: Ctx,
+    Path((network_id, member_id)): Path<(String, String)>,
+) -> Result<Json<Value>> {
+    let member = member::remove_member(&ctx, &network_id, &member_id).await?;
+    Ok(Json(to_central_member(&network_id, member)?))
+}
+
+/// Adds the fields Central has on members besides the ones of the api.
+fn to_central_member(network_id: &str, member: MemberPayload) -> Result<Value> {
+    let Value::Object(mut member) = serde_json::to_value(member)? else {
+        unreachable!("members serialize to a map");
+    };
+    let node_id = member
+        .get("nodeId")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+
+    member.insert("id".into(), format!("{}-{}", network_id, node_id).into());
+    member.insert(
+        "networkId".into(),
+        member
+            .get("networkId")
+            .filter(|id| !id.is_null())
+            .cloned()
+            .unwrap_or_else(|| network_id.into()),
+    );
+    member.insert(
+        "controllerId".into(),
+        network_id.get(..10).unwrap_or_default().into(),
+    );
+    let last_seen = member.get("lastSeen").cloned().unwrap_or_default();
+    member.insert("lastOnline".into(), last_seen);
+    let rules_engine = member.remove("supportsRuleEngine").unwrap_or_default();
+    member.insert("supportsRulesEngine".into(), rules_engine);
+
+    Ok(Value::Object(member))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_to_central_member() {
+        let member = MemberPayload {
+            node_id: Some("aa00000001".to_string()),
+            last_seen: Some(1000),
+            ..Default::default()
+        };
+        let member = to_central_member("abcdef0123000001", member).unwrap();
+        assert_eq!(member["id"], json!("abcdef0123000001-aa00000001"));
+        assert_eq!(member["networkId"], json!("abcdef0123000001"));
+        assert_eq!(member["controllerId"], json!("abcdef0123"));
+        assert_eq!(member["lastOnline"], json!(1000));
+        assert!(member.get("supportsRuleEngine").is_none());
+        assert!(member.get("supportsRulesEngine").is_some());
+    }
+}
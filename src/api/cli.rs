@@ -0,0 +1,124 @@
+//! Operations of the command line, on the same controller client and sidecar
+//! files as the http api.
+
+use serde_json::{Map, Value};
+
+use super::{
+    ctx::Ctx,
+    member::{self, MemberPayload},
+    network::{self, NetworkPalyload},
+    ApiError, Result, SharedState,
+};
+
+/// The controller as seen by the http api, with the token of the token file.
+pub struct Controller {
+    ctx: Ctx,
+}
+
+impl Controller {
+    pub fn new(state: &SharedState) -> Result<Self> {
+        let token = state
+            .default_controller()
+            .token
+            .get()
+            .ok_or(ApiError::Unauthorized)?;
+        Ok(Self {
+            ctx: Ctx::new(state.clone(), Some(token)),
+        })
+    }
+
+    pub async fn networks(&self) -> Result<Value> {
+        Ok(serde_json::to_value(
+            network::list_networks(&self.ctx).await?,
+        )?)
+    }
+
+    pub async fn network(&self, network_id: &str) -> Result<Value> {
+        Ok(serde_json::to_value(
+            network::fetch_network(&self.ctx, network_id).await?,
+        )?)
+    }
+
+    pub async fn create_network(
+        &self,
+        name: Option<String>,
+        description: Option<String>,
+    ) -> Result<Value> {
+        let mut config = Map::new();
+        if let Some(name) = name {
+            config.insert("name".to_string(), name.into());
+        }
+        let network = NetworkPalyload {
+            config: Some(config),
+            description,
+            ..Default::default()
+        };
+        Ok(serde_json::to_value(
+            network::add_network(&self.ctx, network).await?,
+        )?)
+    }
+
+    pub async fn delete_network(&self, network_id: &str) -> Result<Value> {
+        Ok(serde_json::to_value(
+            network::remove_network(&self.ctx, network_id).await?,
+        )?)
+    }
+
+    pub async fn members(&self, network_id: &str) -> Result<Value> {
+        Ok(serde_json::to_value(
+            member::list_members(&self.ctx, network_id).await?,
+        )?)
+    }
+
+    pub async fn authorize_member(
+        &self,
+        network_id: &str,
+        member_id: &str,
+        authorized: bool,
+    ) -> Result<Value> {
+        let member = MemberPayload {
+            config: Some(Map::from_iter([(
+                "authorized".to_string(),
+                authorized.into(),
+            )])),
+            ..Default::default()
+        };
+        self.update_member(network_id, member_id, member).await
+    }
+
+    pub async fn rename_member(
+        &self,
+        network_id: &str,
+        member_id: &str,
+        name: String,
+    ) -> Result<Value> {
+        let member = MemberPayload {
+            name: Some(name),
+            ..Default::default()
+        };
+        self.update_member(network_id, member_id, member).await
+    }
+
+    async fn update_member(
+        &self,
+        network_id: &str,
+        member_id: &str,
+        member: MemberPayload,
+    ) -> Result<Value> {
+        // the controller creates unknown members on update.
+        self.ctx.get_member(network_id, member_id).await?;
+        Ok(serde_json::to_value(
+            member::apply_member_update(&self.ctx, network_id, member_id, member).await?,
+        )?)
+    }
+
+    pub async fn delete_member(&self, network_id: &str, member_id: &str) -> Result<Value> {
+        Ok(serde_json::to_value(
+            member::remove_member(&self.ctx, network_id, member_id).await?,
+        )?)
+    }
+
+    pub async fn peers(&self) -> Result<Value> {
+        Ok(serde_json::to_value(self.ctx.get_peers().await?)?)
+    }
+}
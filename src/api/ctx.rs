@@ -1,9 +1,16 @@
-use std::path::Path;
+use std::{path::Path, sync::Arc, time::Duration};
 
-use super::{ApiError, Result, SharedState};
+use super::{
+    cache::{Cache, Snapshot},
+    ApiError, ControllerState, Result, SharedState,
+};
 use async_trait::async_trait;
-use axum::{extract::FromRequestParts, http::request::Parts};
+use axum::{
+    extract::FromRequestParts,
+    http::{header::AUTHORIZATION, request::Parts, HeaderMap},
+};
 use reqwest::Client;
+use tokio::sync::{MutexGuard, Semaphore};
 
 const ZT1_AUTH_TOKEN: &str = "X-ZT1-AUTH";
 
@@ -11,11 +18,88 @@ const ZT1_AUTH_TOKEN: &str = "X-ZT1-AUTH";
 pub struct Ctx {
     zt1_token: Option<String>,
     state: SharedState,
+    controller: Arc<ControllerState>,
+    use_cache: bool,
 }
 
+/// The controller a request is scoped to, set by `/api/v1/controllers/{name}`.
+#[derive(Debug, Clone)]
+pub struct Scope(pub Arc<ControllerState>);
+
 impl Ctx {
+    /// Context for the default controller.
+    pub fn new(state: SharedState, zt1_token: Option<String>) -> Self {
+        let controller = state.default_controller().clone();
+        Ctx {
+            zt1_token,
+            state,
+            controller,
+            use_cache: true,
+        }
+    }
+
+    /// Talk to `controller` instead.
+    pub fn on(mut self, controller: Arc<ControllerState>) -> Self {
+        self.controller = controller;
+        self
+    }
+
+    /// Context for background tasks of the default controller.
+    pub fn background(state: &SharedState) -> Option<Self> {
+        Self::background_on(state, state.default_controller())
+    }
+
+    /// Context for background tasks, using the token file or the last token
+    /// the controller accepted.
+    pub fn background_on(state: &SharedState, controller: &Arc<ControllerState>) -> Option<Self> {
+        let token = controller
+            .token
+            .get()
+            .or_else(|| controller.cache.token())?;
+        Some(Ctx::new(state.clone(), Some(token)).on(controller.clone()))
+    }
+
+    /// Context for a request to `controller`, with the token of the request.
+    ///
+    /// The token file of the default controller is used for requests to it
+    /// bringing the `auth_token`, or no token with `allow_anonymous`.
+    pub fn for_request(
+        state: &SharedState,
+        headers: &HeaderMap,
+        controller: Arc<ControllerState>,
+    ) -> Self {
+        let header = |name| headers.get(name).and_then(|x| x.to_str().ok());
+        let token = header(ZT1_AUTH_TOKEN)
+            .or_else(|| header(AUTHORIZATION.as_str()).and_then(bearer_token));
+        let token_file = || {
+            Arc::ptr_eq(&controller, state.default_controller())
+                .then(|| controller.token.get())
+                .flatten()
+        };
+        let zt1_token = match token {
+            Some(token)
+                if state
+                    .auth_token
+                    .as_deref()
+                    .is_some_and(|t| secret_eq(t, token)) =>
+            {
+                token_file()
+            }
+            Some(token) => Some(token.to_string()),
+            None if state.allow_anonymous => token_file(),
+            None => None,
+        };
+        Ctx::new(state.clone(), zt1_token).on(controller)
+    }
+
+    /// Always talk to the controller, e.g. to refresh the cache itself.
+    pub fn without_cache(mut self) -> Self {
+        self.use_cache = false;
+        self
+    }
+
     pub fn base_url(&self) -> &str {
-        self.state.api.as_str()
+        self.controller.api.as_str()
     }
 
     pub fn zt1_token(&self) -> Option<&str> {
@@ -23,11 +107,44 @@ impl Ctx {
     }
 
     pub fn work_dir(&self) -> &Path {
-        &self.state.work_dir
+        &self.controller.work_dir
+    }
+
+    /// The work dir of the instance, shared by all controllers.
+    pub fn instance_work_dir(&self) -> &Path {
+        &self.state.default_controller().work_dir
     }
 
     pub fn http_client(&self) -> &Client {
-        &self.state.client
+        &self.controller.client
+    }
+
+    pub fn limiter(&self) -> &Semaphore {
+        &self.controller.limiter
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.state.retries
+    }
+
+    pub fn online_threshold(&self) -> Duration {
+        self.state.online_threshold
+    }
+
+    /// Serializes updates based on the current state, e.g. with `If-Match`.
+    pub async fn lock_updates(&self) -> MutexGuard<'_, ()> {
+        self.controller.update_lock.lock().await
+    }
+
+    pub fn cache(&self) -> &Cache {
+        &self.controller.cache
+    }
+
+    pub fn cached<T>(&self, f: impl FnOnce(&Snapshot) -> T) -> Option<T> {
+        if !self.use_cache {
+            return None;
+        }
+        self.cache().get(self.zt1_token(), f)
     }
 }
 
@@ -36,14 +153,47 @@ impl FromRequestParts<SharedState> for Ctx {
     type Rejection = ApiError;
 
     async fn from_request_parts(parts: &mut Parts, state: &SharedState) -> Result<Self> {
-        let zt1_token = parts
-            .headers
-            .get(ZT1_AUTH_TOKEN)
-            .and_then(|x| x.to_str().ok())
-            .map(|s| s.to_string());
-        Ok(Ctx {
-            zt1_token,
-            state: state.clone(),
-        })
+        let controller = match parts.extensions.get::<Scope>() {
+            Some(Scope(controller)) => controller.clone(),
+            None => state.default_controller().clone(),
+        };
+        Ok(Ctx::for_request(state, &parts.headers, controller))
+    }
+}
+
+/// The token of an `Authorization` header, `Bearer <token>` or `token <token>`
+/// as used by ZeroTier Central.
+fn bearer_token(value: &str) -> Option<&str> {
+    let (scheme, token) = value.trim().split_once(' ')?;
+    (scheme.eq_ignore_ascii_case("bearer") || scheme.eq_ignore_ascii_case("token"))
+        .then(|| token.trim())
+}
+
+/// Compares secrets in a time independent of where they differ.
+fn secret_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bearer_token, secret_eq};
+
+    #[test]
+    fn test_bearer_token() {
+        assert_eq!(bearer_token("Bearer abc"), Some("abc"));
+        assert_eq!(bearer_token("token abc "), Some("abc"));
+        assert_eq!(bearer_token("Basic abc"), None);
+        assert_eq!(bearer_token("abc"), None);
+    }
+
+    #[test]
+    fn test_secret_eq() {
+        assert!(secret_eq("abc", "abc"));
+        assert!(!secret_eq("abc", "abd"));
+        assert!(!secret_eq("abc", "ab"));
     }
 }
@@ -0,0 +1,338 @@
+//! Diagnosis of the installation for the `doctor` subcommand.
+
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{
+    ctx::Ctx, health::check_writable, member::MemberPayload, network::NetworkPalyload, SharedState,
+};
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub checks: Vec<Finding>,
+}
+
+impl Report {
+    pub fn failed(&self) -> bool {
+        self.checks.iter().any(|c| c.status == Status::Fail)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Finding {
+    pub name: &'static str,
+    pub status: Status,
+    pub message: String,
+    /// what to do about a problem.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// works, but something should be looked at.
+    Warn,
+    Fail,
+    /// the check could not run because an earlier one failed.
+    Skip,
+}
+
+impl Finding {
+    fn new(name: &'static str, status: Status, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            status,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+/// Checks the work dir, the token file, the controller and the sidecar files.
+///
+/// `work_dir_source` tells where the work dir comes from. `token_file` is the
+/// configured or discovered file and whether it was configured, the state
+/// holds its token when it could be read.
+pub async fn diagnose(
+    state: &SharedState,
+    work_dir_source: &str,
+    token_file: Option<(PathBuf, bool)>,
+) -> Report {
+    let mut checks = vec![check_work_dir(
+        &state.default_controller().work_dir,
+        work_dir_source,
+    )];
+    checks.push(check_token_file(token_file));
+
+    let ctx = Ctx::new(state.clone(), state.default_controller().token.get()).without_cache();
+
+    let probe = ctx.probe_status().await;
+    checks.push(match &probe {
+        Ok(status) => Finding::new(
+            "controller",
+            Status::Ok,
+            format!("{} responded {}", ctx.base_url(), status),
+        ),
+        Err(err) => Finding::new(
+            "controller",
+            Status::Fail,
+            format!("{}: {}", ctx.base_url(), err),
+        )
+        .hint("check that zerotier-one is running, and --zt-api or zerotier-one.port in --zt-home"),
+    });
+
+    let authorized = matches!(probe, Ok(status) if status.is_success());
+    checks.push(match (&probe, ctx.zt1_token()) {
+        (Err(_), _) => Finding::new("token", Status::Skip, "controller unreachable"),
+        (Ok(_), None) => Finding::new("token", Status::Skip, "no token"),
+        (Ok(_), Some(_)) if authorized => {
+            Finding::new("token", Status::Ok, "accepted by the controller")
+        }
+        (Ok(status), Some(_)) => Finding::new(
+            "token",
+            Status::Fail,
+            format!("rejected by the controller with {}", status),
+        )
+        .hint("use the authtoken.secret of the zerotier-one the api address points to"),
+    });
+
+    if !authorized {
+        checks.push(Finding::new(
+            "controller mode",
+            Status::Skip,
+            "needs an accepted token",
+        ));
+        checks.push(check_sidecars(&ctx, None).await);
+        return Report { checks };
+    }
+
+    checks.push(match ctx.get_controller().await {
+        Ok(Some(controller)) => Finding::new(
+            "controller mode",
+            Status::Ok,
+            format!(
+                "enabled, api version {}",
+                controller
+                    .get("apiVersion")
+                    .filter(|v| !v.is_null())
+                    .map_or("unknown".to_string(), |v| v.to_string())
+            ),
+        ),
+        Ok(None) => Finding::new(
+            "controller mode",
+            Status::Fail,
+            "the node does not act as a network controller",
+        )
+        .hint("use a build of zerotier-one with the controller, the default packages have it"),
+        Err(err) => Finding::new("controller mode", Status::Fail, err.to_string()),
+    });
+
+    let network_ids = ctx.get_network_ids().await.ok();
+    checks.push(check_sidecars(&ctx, network_ids).await);
+
+    Report { checks }
+}
+
+fn check_work_dir(work_dir: &Path, source: &str) -> Finding {
+    let name = "work dir";
+    let work_dir_of = |message: String| format!("{}, {}", message, source);
+    match fs::metadata(work_dir) {
+        Ok(meta) if meta.is_dir() => (),
+        Ok(_) => {
+            return Finding::new(
+                name,
+                Status::Fail,
+                work_dir_of(format!("{:?} is not a directory", work_dir)),
+            )
+            .hint("set --work-dir to a directory")
+        }
+        Err(err) => {
+            return Finding::new(
+                name,
+                Status::Fail,
+                work_dir_of(format!("{:?}: {}", work_dir, err)),
+            )
+            .hint("create it, or set --work-dir to the zerotier home")
+        }
+    }
+    match check_writable(work_dir) {
+        Ok(()) => Finding::new(
+            name,
+            Status::Ok,
+            work_dir_of(format!("{:?} is writable", work_dir)),
+        ),
+        Err(err) => Finding::new(
+            name,
+            Status::Fail,
+            work_dir_of(format!("{:?}: {}", work_dir, err)),
+        )
+        .hint("run as the owner of the directory, e.g. the zerotier-one user"),
+    }
+}
+
+fn check_token_file(token_file: Option<(PathBuf, bool)>) -> Finding {
+    let name = "token file";
+    let Some((path, configured)) = token_file else {
+        return Finding::new(name, Status::Warn, "no zerotier home on this platform")
+            .hint("set --token-file or --zt-home");
+    };
+
+    match fs::read_to_string(&path) {
+        Ok(token) if token.trim().is_empty() => {
+            Finding::new(name, Status::Fail, format!("{:?} is empty", path))
+        }
+        Ok(_) => Finding::new(name, Status::Ok, format!("{:?} is readable", path)),
+        Err(err) => {
+            let hint = match err.kind() {
+                io::ErrorKind::PermissionDenied => {
+                    "run as root or the zerotier-one user, or copy the token to a readable --token-file"
+                }
+                _ => "set --token-file, or --zt-home to the directory of authtoken.secret",
+            };
+            // without a discovered token, requests still work with a token of their own.
+            let status = if configured {
+                Status::Fail
+            } else {
+                Status::Warn
+            };
+            Finding::new(name, status, format!("{:?}: {}", path, err)).hint(hint)
+        }
+    }
+}
+
+/// Looks for sidecar files that do not parse, and with `network_ids` for
+/// those of networks and members the controller does not have.
+async fn check_sidecars(ctx: &Ctx, network_ids: Option<Vec<String>>) -> Finding {
+    let name = "sidecar files";
+    let dir = ctx.work_dir().join("controller.d").join("network");
+
+    let mut corrupt = vec![];
+    let mut orphaned = vec![];
+    let mut files = 0;
+
+    let networks = match sidecars(&dir) {
+        Ok(networks) => networks,
+        Err(err) => return Finding::new(name, Status::Fail, format!("{:?}: {}", dir, err)),
+    };
+    let known = network_ids.map(HashSet::<String>::from_iter);
+
+    for (network_id, path) in networks {
+        files += 1;
+        if !parses::<NetworkPalyload>(&path) {
+            corrupt.push(path);
+        } else if known.as_ref().is_some_and(|k| !k.contains(&network_id)) {
+            orphaned.push(path);
+        }
+    }
+
+    let network_dirs = fs::read_dir(&dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|e| e.path().is_dir());
+    for entry in network_dirs {
+        let network_id = entry.file_name().to_string_lossy().to_string();
+        let member_dir = entry.path().join("member");
+        let members = match sidecars(&member_dir) {
+            Ok(members) => members,
+            Err(err) => {
+                return Finding::new(name, Status::Fail, format!("{:?}: {}", member_dir, err))
+            }
+        };
+        let member_ids = match &known {
+            Some(known) if known.contains(&network_id) => {
+                ctx.get_member_ids(&network_id).await.ok()
+            }
+            _ => None,
+        };
+
+        for (member_id, path) in members {
+            files += 1;
+            if !parses::<MemberPayload>(&path) {
+                corrupt.push(path);
+            } else if known.as_ref().is_some_and(|k| !k.contains(&network_id))
+                || member_ids
+                    .as_ref()
+                    .is_some_and(|m| !m.contains_key(&member_id))
+            {
+                orphaned.push(path);
+            }
+        }
+    }
+
+    let list = |paths: &[PathBuf]| {
+        paths
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if !corrupt.is_empty() {
+        Finding::new(
+            name,
+            Status::Fail,
+            format!(
+                "{} of {} do not parse: {}",
+                corrupt.len(),
+                files,
+                list(&corrupt)
+            ),
+        )
+        .hint("fix or remove them, the networks and members lose their names and descriptions")
+    } else if !orphaned.is_empty() {
+        Finding::new(
+            name,
+            Status::Warn,
+            format!(
+                "{} of {} belong to deleted networks or members: {}",
+                orphaned.len(),
+                files,
+                list(&orphaned)
+            ),
+        )
+        .hint("remove them, they are not used")
+    } else {
+        Finding::new(name, Status::Ok, format!("{} files", files))
+    }
+}
+
+/// The `<id>.ext.json` files in `dir`, a missing directory has none.
+fn sidecars(dir: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+
+    let mut files = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        let id = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_suffix(".ext.json"));
+        if let Some(id) = id {
+            files.push((id.to_string(), path.clone()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn parses<T: DeserializeOwned>(path: &Path) -> bool {
+    fs::read(path)
+        .ok()
+        .is_some_and(|data| serde_json::from_slice::<T>(&data).is_ok())
+}
@@ -0,0 +1,93 @@
+//! Entity tags of networks and members, for optimistic concurrency control
+//! with `If-Match`.
+
+use axum::http::{
+    header::{ETAG, IF_MATCH},
+    HeaderMap, HeaderValue,
+};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use super::{ApiError, Result};
+
+/// Tag of the revision of a controller config and the metadata stored in the
+/// sidecar, quoted as in the `ETag` header. Stays the same across builds, as
+/// clients keep it.
+pub(super) fn compute(config: Option<&Map<String, Value>>, metadata: &impl Serialize) -> String {
+    let revision = config
+        .and_then(|c| c.get("revision"))
+        .and_then(Value::as_i64);
+    let metadata = serde_json::to_string(metadata).unwrap_or_default();
+    let input = format!("{:?}\0{}", revision, metadata);
+    format!("\"{:016x}\"", fnv1a(input.as_bytes()))
+}
+
+/// 64-bit FNV-1a.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Headers of a response carrying `etag`.
+pub(super) fn header(etag: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Ok(value) = HeaderValue::from_str(etag) {
+        headers.insert(ETAG, value);
+    }
+    headers
+}
+
+/// The `If-Match` header of a request, if any.
+pub(super) fn if_match(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(IF_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Fails with 412 unless `if_match` lists `etag` or is `*`.
+pub(super) fn check(if_match: &str, etag: &str) -> Result<()> {
+    let matched = if_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag);
+    if matched {
+        Ok(())
+    } else {
+        Err(ApiError::PreconditionFailed(format!(
+            "the resource was modified, its current etag is {}",
+            etag
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_etag() {
+        let config = json!({"id": "abcdef0123000001", "revision": 3});
+        let config = config.as_object();
+        let etag = compute(config, &json!({"name": "a"}));
+
+        assert_eq!(etag, compute(config, &json!({"name": "a"})));
+        assert_ne!(etag, compute(config, &json!({"name": "b"})));
+        let revised = json!({"id": "abcdef0123000001", "revision": 4});
+        assert_ne!(etag, compute(revised.as_object(), &json!({"name": "a"})));
+
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+
+        assert!(check(&etag, &etag).is_ok());
+        assert!(check(&format!("\"x\", W/{}", etag), &etag).is_ok());
+        assert!(check("*", &etag).is_ok());
+        assert!(matches!(
+            check("\"x\"", &etag),
+            Err(ApiError::PreconditionFailed(_))
+        ));
+    }
+}
@@ -0,0 +1,65 @@
+//! `Json` and `Query` rejecting invalid requests with the error body of the
+//! api instead of the plain text of axum.
+
+use async_trait::async_trait;
+use axum::{
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        FromRequest, FromRequestParts, Request,
+    },
+    http::request::Parts,
+    response::{IntoResponse, Response},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{ApiError, FieldError};
+
+pub(super) struct Json<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned, S: Send + Sync> FromRequest<S> for Json<T> {
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, ApiError> {
+        match axum::Json::<T>::from_request(request, state).await {
+            Ok(axum::Json(value)) => Ok(Json(value)),
+            Err(rejection) => Err(from_json_rejection(rejection)),
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+/// Data errors name the field, from the path axum deserializes with.
+fn from_json_rejection(rejection: JsonRejection) -> ApiError {
+    let JsonRejection::JsonDataError(err) = &rejection else {
+        return ApiError::BadRequest(rejection.body_text());
+    };
+    let source = std::iter::successors(std::error::Error::source(err), |s| s.source())
+        .find_map(|s| s.downcast_ref::<serde_path_to_error::Error<serde_json::Error>>());
+    match source {
+        Some(source) => ApiError::InvalidFields(vec![FieldError {
+            field: source.path().to_string(),
+            message: source.inner().to_string(),
+        }]),
+        None => ApiError::BadRequest(rejection.body_text()),
+    }
+}
+
+pub(super) struct Query<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned, S: Send + Sync> FromRequestParts<S> for Query<T> {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, ApiError> {
+        axum::extract::Query::<T>::from_request_parts(parts, state)
+            .await
+            .map(|axum::extract::Query(value)| Query(value))
+            .map_err(|rejection: QueryRejection| ApiError::BadRequest(rejection.body_text()))
+    }
+}
@@ -0,0 +1,192 @@
+//! Several controllers managed by one instance: `/api/v1/controllers/{name}`
+//! serves the routes of `/api/v1` for the named controller, and the overview
+//! lists the networks of all of them.
+
+use std::sync::LazyLock;
+
+use axum::{
+    extract::{OriginalUri, Path, Request, State},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    routing::{any, get},
+    Router,
+};
+use futures::future::join_all;
+use serde::Serialize;
+use tower::ServiceExt;
+use utoipa::ToSchema;
+
+use super::{
+    ctx::{Ctx, Scope},
+    extract::Json,
+    network::{list_networks, NetworkPalyload},
+    v1_routes, ApiError, Result, SharedState,
+};
+
+/// The routes of `/api/v1` the scoped requests are dispatched to.
+static SCOPED: LazyLock<Router<SharedState>> =
+    LazyLock::new(|| Router::new().nest("/api/v1", v1_routes()));
+
+#[inline]
+pub fn routes() -> Router<SharedState> {
+    Router::new()
+        .route("/controllers", get(get_controllers))
+        .route("/controllers/:controller/*path", any(scoped))
+        .route("/overview", get(get_overview))
+}
+
+/// Serves a request of `/api/v1/controllers/{name}/...` as the one of
+/// `/api/v1/...`, for the controller.
+async fn scoped(
+    State(state): State<SharedState>,
+    Path((name, _)): Path<(String, String)>,
+    request: Request,
+) -> Result<Response> {
+    let controller = state
+        .controller(&name)
+        .ok_or_else(|| ApiError::ControllerNotFound(name.clone()))?
+        .clone();
+
+    // the raw path, the one of the parameter is decoded.
+    let (parts, body) = request.into_parts();
+    let prefix = format!("/controllers/{}/", name);
+    let path = parts
+        .uri
+        .path()
+        .split_once(&prefix)
+        .map(|(_, path)| path)
+        .unwrap_or_default();
+    let uri = match parts.uri.query() {
+        Some(query) => format!("/api/v1/{}?{}", path, query),
+        None => format!("/api/v1/{}", path),
+    };
+
+    // a new request, the handlers would see the path parameters of this route.
+    let mut request = Request::builder()
+        .method(parts.method)
+        .uri(uri)
+        .version(parts.version)
+        .body(body)?;
+    *request.headers_mut() = parts.headers;
+    if let Some(original_uri) = parts.extensions.get::<OriginalUri>() {
+        request.extensions_mut().insert(original_uri.clone());
+    }
+    request.extensions_mut().insert(Scope(controller));
+
+    let router = SCOPED.clone().with_state(state);
+    Ok(router.oneshot(request).await.into_response())
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct Controller {
+    name: String,
+    api: String,
+    /// the one served by `/api/v1` itself.
+    default: bool,
+    status: ControllerStatus,
+    /// the response or the error of the controller.
+    message: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum ControllerStatus {
+    Online,
+    /// the token of the request was rejected.
+    Unauthorized,
+    Unreachable,
+}
+
+/// The controllers with whether they respond to the token of the request, which
+/// must be accepted by the default controller.
+#[utoipa::path(
+    get,
+    path = "/controllers",
+    tag = "controller",
+    responses((status = 200, body = Vec<Controller>)),
+)]
+async fn get_controllers(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Controller>>> {
+    Ctx::for_request(&state, &headers, state.default_controller().clone())
+        .authorize()
+        .await?;
+
+    let controllers = state.controllers.iter().enumerate().map(|(i, controller)| {
+        let ctx = Ctx::for_request(&state, &headers, controller.clone()).without_cache();
+        async move {
+            let (status, message) = match ctx.probe_status().await {
+                Ok(status) if status.is_success() => (ControllerStatus::Online, status.to_string()),
+                Ok(status) => (ControllerStatus::Unauthorized, status.to_string()),
+                Err(err) => (ControllerStatus::Unreachable, err.to_string()),
+            };
+            Controller {
+                name: controller.name.clone(),
+                api: controller.api.clone(),
+                default: i == 0,
+                status,
+                message,
+            }
+        }
+    });
+    Ok(Json(join_all(controllers).await))
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct Overview {
+    networks: Vec<ControllerNetwork>,
+    /// controllers whose networks could not be listed.
+    errors: Vec<ControllerError>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ControllerNetwork {
+    controller: String,
+    #[serde(flatten)]
+    network: NetworkPalyload,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ControllerError {
+    controller: String,
+    code: &'static str,
+    message: String,
+}
+
+/// The networks of every controller, a controller failing does not fail the
+/// others.
+#[utoipa::path(
+    get,
+    path = "/overview",
+    tag = "controller",
+    responses((status = 200, body = Overview)),
+)]
+async fn get_overview(State(state): State<SharedState>, headers: HeaderMap) -> Json<Overview> {
+    let results = join_all(state.controllers.iter().map(|controller| {
+        let ctx = Ctx::for_request(&state, &headers, controller.clone());
+        async move { (controller.name.clone(), list_networks(&ctx).await) }
+    }))
+    .await;
+
+    let mut overview = Overview {
+        networks: vec![],
+        errors: vec![],
+    };
+    for (controller, result) in results {
+        match result {
+            Ok(networks) => overview
+                .networks
+                .extend(networks.into_iter().map(|network| ControllerNetwork {
+                    controller: controller.clone(),
+                    network,
+                })),
+            Err(err) => overview.errors.push(ControllerError {
+                controller,
+                code: err.code(),
+                message: err.to_string(),
+            }),
+        }
+    }
+    Json(overview)
+}
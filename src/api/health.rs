@@ -0,0 +1,148 @@
+use std::{
+    fs, io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use super::{ctx::Ctx, SharedState};
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Readiness {
+    status: CheckStatus,
+    checks: Vec<Check>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Check {
+    name: &'static str,
+    status: CheckStatus,
+    message: String,
+    duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum CheckStatus {
+    Ok,
+    Fail,
+    /// the check could not run, does not fail the readiness.
+    Skip,
+}
+
+impl Check {
+    fn new(name: &'static str, duration: Duration, result: (CheckStatus, String)) -> Self {
+        Self {
+            name,
+            status: result.0,
+            message: result.1,
+            duration_ms: duration.as_millis() as u64,
+        }
+    }
+}
+
+/// The process is alive and serving requests.
+pub async fn get_healthz() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+/// Checks everything a request depends on, 503 if any check fails.
+///
+/// Uses the token of the request, or the last token accepted by the
+/// controller when there is none, and fails without either. Addresses and
+/// paths are left out, the endpoint needs no token.
+pub async fn get_readyz(
+    ctx: Ctx,
+    State(state): State<SharedState>,
+) -> (StatusCode, Json<Readiness>) {
+    let ctx = match ctx.zt1_token() {
+        Some(_) => ctx,
+        None => Ctx::background(&state).unwrap_or(ctx),
+    }
+    .without_cache();
+
+    let mut checks = Vec::with_capacity(4);
+
+    let started = Instant::now();
+    let probe = ctx.probe_status().await;
+    let duration = started.elapsed();
+    checks.push(Check::new(
+        "controller",
+        duration,
+        match &probe {
+            Ok(status) => (CheckStatus::Ok, format!("responded {}", status)),
+            Err(err) => (CheckStatus::Fail, err.code().to_string()),
+        },
+    ));
+
+    let token = match (&probe, ctx.zt1_token()) {
+        (Err(_), _) => (CheckStatus::Skip, "controller unreachable".to_string()),
+        (Ok(_), None) => (
+            CheckStatus::Fail,
+            "no token, none was sent and none was accepted yet".to_string(),
+        ),
+        (Ok(status), Some(_)) if status.is_success() => {
+            (CheckStatus::Ok, "token accepted".to_string())
+        }
+        (Ok(status), Some(_)) => (CheckStatus::Fail, format!("token rejected with {}", status)),
+    };
+    // answered by the same request as the controller check.
+    checks.push(Check::new("token", duration, token));
+
+    let started = Instant::now();
+    let result = check_writable(ctx.work_dir());
+    checks.push(Check::new(
+        "workDir",
+        started.elapsed(),
+        match result {
+            Ok(()) => (CheckStatus::Ok, "writable".to_string()),
+            Err(err) => (CheckStatus::Fail, err.to_string()),
+        },
+    ));
+
+    let started = Instant::now();
+    let sidecar_dir = ctx.work_dir().join("controller.d");
+    let result = check_accessible(&sidecar_dir);
+    checks.push(Check::new(
+        "sidecarStorage",
+        started.elapsed(),
+        match result {
+            Ok(false) => (CheckStatus::Ok, "not created yet".to_string()),
+            Ok(true) => (CheckStatus::Ok, "accessible".to_string()),
+            Err(err) => (CheckStatus::Fail, err.to_string()),
+        },
+    ));
+
+    let status = if checks.iter().any(|c| c.status == CheckStatus::Fail) {
+        CheckStatus::Fail
+    } else {
+        CheckStatus::Ok
+    };
+    let code = match status {
+        CheckStatus::Fail => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::OK,
+    };
+
+    (code, Json(Readiness { status, checks }))
+}
+
+pub(super) fn check_writable(dir: &Path) -> io::Result<()> {
+    let probe = dir.join(".zerotier-edge-probe");
+    fs::write(&probe, b"")?;
+    fs::remove_file(probe)
+}
+
+/// Whether `dir` exists, without reading the files in it. Writes are probed
+/// once by the work dir check.
+fn check_accessible(dir: &Path) -> io::Result<bool> {
+    match fs::read_dir(dir) {
+        Ok(_) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use utoipa::IntoParams;
+
+use super::{ctx::Ctx, peer, presence, ControllerState, SharedState};
+use crate::log;
+
+const COMPACT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_WINDOW_MS: i64 = 7 * 24 * 60 * 60 * 1000;
+
+/// Time range of a history query.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct Window {
+    /// start of the window in milliseconds, default: 7 days before `until`.
+    since: Option<i64>,
+    /// end of the window in milliseconds, default: now.
+    until: Option<i64>,
+}
+
+impl Window {
+    pub fn range(&self) -> (i64, i64) {
+        let until = self.until.unwrap_or_else(now_millis);
+        let since = self.since.unwrap_or(until - DEFAULT_WINDOW_MS);
+        (since.min(until), until)
+    }
+}
+
+/// Root of the time series recorded by the sampler, one JSON line per entry.
+pub fn history_dir(work_dir: &Path) -> PathBuf {
+    work_dir.join("history.d")
+}
+
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+pub fn append<T: Serialize>(file_path: &Path, entry: &T) -> io::Result<()> {
+    if let Some(dir) = file_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut file = File::options().create(true).append(true).open(file_path)?;
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    file.write_all(&line)
+}
+
+/// Reads all entries of a file, skipping lines that cannot be parsed.
+pub fn read<T: DeserializeOwned>(file_path: &Path) -> io::Result<Vec<T>> {
+    let file = match File::open(file_path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+
+    Ok(BufReader::new(file)
+        .lines()
+        .map_while(|line| line.ok())
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .collect())
+}
+
+/// Rewrites a file keeping the entries from index `from` on.
+fn truncate_front<T: Serialize + DeserializeOwned>(
+    file_path: &Path,
+    from: impl FnOnce(&[T]) -> usize,
+) -> io::Result<()> {
+    let entries = read::<T>(file_path)?;
+    let from = from(&entries);
+    if from == 0 {
+        return Ok(());
+    }
+
+    let tmp_path = file_path.with_extension("jsonl.tmp");
+    let mut file = File::create(&tmp_path)?;
+    for entry in &entries[from..] {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+    }
+    std::fs::rename(tmp_path, file_path)
+}
+
+/// Drops the entries older than `cutoff` from every `*.jsonl` file under `dir`,
+/// `keep_last` keeps the newest expired entry, e.g. to know a state at `cutoff`.
+pub fn compact<T: Serialize + DeserializeOwned>(
+    dir: &Path,
+    cutoff: i64,
+    time: impl Fn(&T) -> i64 + Copy,
+    keep_last: bool,
+) -> io::Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            compact(&path, cutoff, time, keep_last)?;
+        } else if path.extension().is_some_and(|ext| ext == "jsonl") {
+            truncate_front::<T>(&path, |entries| {
+                let expired = entries.iter().take_while(|e| time(e) < cutoff).count();
+                if keep_last {
+                    expired.saturating_sub(1)
+                } else {
+                    expired
+                }
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Periodically samples the controller state into the history files.
+pub fn spawn_sampler(state: SharedState, controller: Arc<ControllerState>) {
+    if state.sample_interval.is_zero() {
+        return;
+    }
+
+    state.tasks.clone().spawn(async move {
+        let mut presence = presence::Sampler::new(state.sample_interval);
+        let mut compacted_at: Option<Instant> = None;
+        let mut interval = tokio::time::interval(state.sample_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => (),
+                _ = state.shutdown.cancelled() => break,
+            }
+
+            let Some(ctx) = Ctx::background_on(&state, &controller) else {
+                continue;
+            };
+
+            if let Err(err) = presence.sample(&ctx).await {
+                log::warn!("presence sampling of {} failed: {}", controller.name, err);
+            }
+
+            if let Err(err) = peer::sample(&ctx).await {
+                log::warn!("peer sampling of {} failed: {}", controller.name, err);
+            }
+
+            if compacted_at.is_none_or(|t| t.elapsed() > COMPACT_INTERVAL) {
+                compacted_at = Some(Instant::now());
+                let now = now_millis();
+                let cutoff = now - state.history_retention.as_millis() as i64;
+                if let Err(err) = presence::compact(ctx.work_dir(), cutoff) {
+                    log::warn!("presence history compaction failed: {}", err);
+                }
+                let cutoff = now - state.peer_history_retention.as_millis() as i64;
+                if let Err(err) = peer::compact(ctx.work_dir(), cutoff) {
+                    log::warn!("peer history compaction failed: {}", err);
+                }
+            }
+        }
+    });
+}
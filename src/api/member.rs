@@ -1,15 +1,24 @@
-use std::path::PathBuf;
+use std::{cmp::Ordering, net::IpAddr, path::PathBuf};
 
 use axum::{
     extract::Path,
-    routing::{delete, get, post},
-    Json, Router,
+    http::HeaderMap,
+    routing::{delete, get, patch, post},
+    Router,
 };
 use futures::future::join_all;
 use serde::{Deserialize, Serialize};
-use serde_json::{Map, Value};
-
-use super::{assign_not_none_to, ctx::Ctx, Result, SharedState};
+use serde_json::{json, Map, Value};
+use utoipa::{IntoParams, ToSchema};
+
+use super::{
+    assign_not_none_to,
+    ctx::Ctx,
+    etag,
+    extract::{Json, Query},
+    patch::{changed_config, Patch},
+    peer, write_sidecar, ApiError, Result, SharedState,
+};
 
 #[inline]
 pub fn routes() -> Router<SharedState> {
@@ -24,11 +33,60 @@ pub fn routes() -> Router<SharedState> {
             "/network/:network_id/member/:member_id",
             post(update_member),
         )
+        .route(
+            "/network/:network_id/member/:member_id",
+            patch(patch_member),
+        )
+}
+
+/// Members of a network, filtered, sorted and paginated. The total count is
+/// returned in `X-Total-Count` and the cursor of the next page in `X-Next-Cursor`.
+#[utoipa::path(
+    get,
+    path = "/network/{network_id}/member",
+    tag = "member",
+    params(
+        ("network_id" = String, Path, description = "16 hex digits network id"),
+        MemberFilter,
+        MemberPage,
+    ),
+    responses(
+        (status = 200, body = [MemberPayload], headers(
+            ("X-Total-Count" = usize, description = "number of members matching the filter"),
+            ("X-Next-Cursor" = String, description = "cursor of the next page, if any"),
+        )),
+        (status = 400),
+    ),
+)]
+async fn get_members(
+    ctx: Ctx,
+    Path(network_id): Path<String>,
+    Query(filter): Query<MemberFilter>,
+    Query(page): Query<MemberPage>,
+) -> Result<(HeaderMap, Json<Vec<MemberPayload>>)> {
+    let mut members = list_members(&ctx, &network_id)
+        .await?
+        .into_iter()
+        .filter(|m| filter.matches(m))
+        .collect::<Vec<_>>();
+
+    let total = members.len();
+
+    page.sort(&mut members)?;
+    let (members, next_cursor) = page.paginate(members)?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("X-Total-Count", total.into());
+    if let Some(cursor) = next_cursor.and_then(|c| c.parse().ok()) {
+        headers.insert("X-Next-Cursor", cursor);
+    }
+
+    Ok((headers, Json(members)))
 }
 
-async fn get_members(ctx: Ctx, Path(network_id): Path<String>) -> Result<Json<Vec<MemberPayload>>> {
+pub(super) async fn list_members(ctx: &Ctx, network_id: &str) -> Result<Vec<MemberPayload>> {
     let member_ids = ctx
-        .get_member_ids(network_id.as_str())
+        .get_member_ids(network_id)
         .await?
         .keys()
         .cloned()
@@ -36,55 +94,164 @@ async fn get_members(ctx: Ctx, Path(network_id): Path<String>) -> Result<Json<Ve
 
     let tasks = member_ids
         .iter()
-        .map(|member_id| ctx.get_member(network_id.as_str(), member_id));
+        .map(|member_id| ctx.get_member(network_id, member_id));
 
     let member_configs = join_all(tasks)
         .await
         .into_iter()
         .collect::<Result<Vec<_>>>()?;
 
-    let members = join_all(member_configs.into_iter().map(|config| async {
-        let mut member = MemberPayload::combine_from_file(config, &network_id, ctx.work_dir());
-        member.update(&ctx).await?;
+    join_all(member_configs.into_iter().map(|config| async {
+        let mut member = MemberPayload::combine_from_file(config, network_id, ctx.work_dir());
+        member.update(ctx).await?;
         Ok(member)
     }))
     .await
     .into_iter()
-    .collect::<Result<Vec<_>>>()?;
-
-    Ok(Json(members))
+    .collect::<Result<Vec<_>>>()
 }
 
+#[utoipa::path(
+    get,
+    path = "/network/{network_id}/member/{member_id}",
+    tag = "member",
+    params(
+        ("network_id" = String, Path, description = "16 hex digits network id"),
+        ("member_id" = String, Path, description = "10 hex digits node id"),
+    ),
+    responses(
+        (status = 200, body = MemberPayload, headers(("ETag" = String))),
+        (status = 404),
+    ),
+)]
 async fn get_member(
     ctx: Ctx,
     Path((network_id, member_id)): Path<(String, String)>,
-) -> Result<Json<MemberPayload>> {
-    let member_config = ctx
-        .get_member(network_id.as_str(), member_id.as_str())
-        .await?;
-    let mut member = MemberPayload::combine_from_file(member_config, &network_id, ctx.work_dir());
+) -> Result<(HeaderMap, Json<MemberPayload>)> {
+    let member = fetch_member(&ctx, &network_id, &member_id).await?;
+    Ok((etag::header(&member.etag()), Json(member)))
+}
+
+pub(super) async fn fetch_member(
+    ctx: &Ctx,
+    network_id: &str,
+    member_id: &str,
+) -> Result<MemberPayload> {
+    let member_config = ctx.get_member(network_id, member_id).await?;
+    let mut member = MemberPayload::combine_from_file(member_config, network_id, ctx.work_dir());
 
-    member.update(&ctx).await?;
+    member.update(ctx).await?;
 
-    Ok(Json(member))
+    Ok(member)
 }
 
+/// Updates the given fields, `config` is merged into the controller config.
+#[utoipa::path(
+    post,
+    path = "/network/{network_id}/member/{member_id}",
+    tag = "member",
+    params(
+        ("network_id" = String, Path, description = "16 hex digits network id"),
+        ("member_id" = String, Path, description = "10 hex digits node id"),
+        ("If-Match" = Option<String>, Header, description = "etag of the last read"),
+    ),
+    request_body = MemberPayload,
+    responses(
+        (status = 200, body = MemberPayload, headers(("ETag" = String))),
+        (status = 412),
+    ),
+)]
 async fn update_member(
     ctx: Ctx,
     Path((network_id, member_id)): Path<(String, String)>,
-    Json(mut member): Json<MemberPayload>,
-) -> Result<Json<MemberPayload>> {
+    headers: HeaderMap,
+    Json(member): Json<MemberPayload>,
+) -> Result<(HeaderMap, Json<MemberPayload>)> {
+    // also without `If-Match`, not to land between the check and the write
+    // of another update.
+    let _guard = ctx.lock_updates().await;
+    if let Some(if_match) = etag::if_match(&headers) {
+        let current = fetch_member(&ctx.clone().without_cache(), &network_id, &member_id).await?;
+        etag::check(&if_match, &current.etag())?;
+    }
+
+    let member = apply_member_update(&ctx, &network_id, &member_id, member).await?;
+    Ok((etag::header(&member.etag()), Json(member)))
+}
+
+/// Patches the member as returned by `GET`, a JSON Merge Patch or a JSON
+/// Patch. Removed fields of `config` are reset to their empty or default value.
+#[utoipa::path(
+    patch,
+    path = "/network/{network_id}/member/{member_id}",
+    tag = "member",
+    params(
+        ("network_id" = String, Path, description = "16 hex digits network id"),
+        ("member_id" = String, Path, description = "10 hex digits node id"),
+        ("If-Match" = Option<String>, Header, description = "etag of the last read"),
+    ),
+    request_body(
+        content(
+            (Object = "application/merge-patch+json"),
+            (Vec<Object> = "application/json-patch+json"),
+        ),
+    ),
+    responses(
+        (status = 200, body = MemberPayload, headers(("ETag" = String))),
+        (status = 400),
+        (status = 404),
+        (status = 412),
+        (status = 415),
+    ),
+)]
+async fn patch_member(
+    ctx: Ctx,
+    Path((network_id, member_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    patch: Patch,
+) -> Result<(HeaderMap, Json<MemberPayload>)> {
+    let _guard = ctx.lock_updates().await;
+    let uncached = ctx.clone().without_cache();
+    let current = fetch_member(&uncached, &network_id, &member_id).await?;
+    if let Some(if_match) = etag::if_match(&headers) {
+        etag::check(&if_match, &current.etag())?;
+    }
+
+    let mut document = serde_json::to_value(&current)?;
+    patch.apply(&mut document)?;
+    let mut member: MemberPayload = serde_json::from_value(document)
+        .map_err(|err| ApiError::BadRequest(format!("patched member: {}", err)))?;
+
+    if let Some(config) = changed_config(current.config.as_ref(), member.config.take())? {
+        uncached
+            .update_member(&network_id, &member_id, &config)
+            .await?;
+    }
+    member.write_to_file(&member_file_path(ctx.work_dir(), &network_id, &member_id))?;
+
+    let member = fetch_member(&uncached, &network_id, &member_id).await?;
+    Ok((etag::header(&member.etag()), Json(member)))
+}
+
+/// Applies a partial member update: `config` is merged into the controller
+/// config, the other fields into the `.ext.json` sidecar.
+pub(super) async fn apply_member_update(
+    ctx: &Ctx,
+    network_id: &str,
+    member_id: &str,
+    mut member: MemberPayload,
+) -> Result<MemberPayload> {
     let mut config = member.config.take();
     if let Some(partial_config) = config {
-        let mut member_config = ctx.get_member(&network_id, &member_id).await?;
+        let mut member_config = ctx.get_member(network_id, member_id).await?;
         member_config.extend(partial_config);
         config = Some(
-            ctx.update_member(network_id.as_str(), member_id.as_str(), &member_config)
+            ctx.update_member(network_id, member_id, &member_config)
                 .await?,
         );
     }
 
-    let file_path = member_file_path(ctx.work_dir(), &network_id, &member_id);
+    let file_path = member_file_path(ctx.work_dir(), network_id, member_id);
 
     member = assign_not_none_to(
         &member,
@@ -95,50 +262,102 @@ async fn update_member(
     member.write_to_file(&file_path)?;
 
     if config.is_none() {
-        config = Some(ctx.get_member(&network_id, &member_id).await?);
+        config = Some(ctx.get_member(network_id, member_id).await?);
     }
 
     member.config = config;
-    member.update(&ctx).await?;
+    member.update(ctx).await?;
 
-    Ok(Json(member))
+    Ok(member)
 }
 
+#[utoipa::path(
+    delete,
+    path = "/network/{network_id}/member/{member_id}",
+    tag = "member",
+    params(
+        ("network_id" = String, Path, description = "16 hex digits network id"),
+        ("member_id" = String, Path, description = "10 hex digits node id"),
+    ),
+    responses((status = 200, body = MemberPayload), (status = 404)),
+)]
 async fn delete_member(
     ctx: Ctx,
     Path((network_id, member_id)): Path<(String, String)>,
 ) -> Result<Json<MemberPayload>> {
-    let member_config = ctx
-        .delete_member(network_id.as_str(), member_id.as_str())
-        .await?;
-    let member = MemberPayload::combine_from_file(member_config, &network_id, ctx.work_dir());
+    Ok(Json(remove_member(&ctx, &network_id, &member_id).await?))
+}
 
-    std::fs::remove_file(member_file_path(ctx.work_dir(), &network_id, &member_id))?;
+pub(super) async fn remove_member(
+    ctx: &Ctx,
+    network_id: &str,
+    member_id: &str,
+) -> Result<MemberPayload> {
+    let member_config = ctx.delete_member(network_id, member_id).await?;
+    let member = MemberPayload::combine_from_file(member_config, network_id, ctx.work_dir());
+
+    // members that were never listed have no sidecar.
+    let file_path = member_file_path(ctx.work_dir(), network_id, member_id);
+    match std::fs::remove_file(&file_path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            return Err(ApiError::Sidecar(file_path, err))
+        }
+        _ => (),
+    }
 
-    Ok(Json(member))
+    Ok(member)
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+/// Fields of the controller or derived from the peer, kept out of the sidecar.
+const NOT_STORED: [&str; 7] = [
+    "config",
+    "clock",
+    "lastSeen",
+    "online",
+    "physicalAddress",
+    "clientVersion",
+    "protocolVersion",
+];
+
+/// A member of a network, `config` is the one of the controller, the other
+/// fields are stored by zerotier-edge or derived from the peer.
+#[derive(Debug, Serialize, Deserialize, Default, ToSchema)]
 #[serde(rename_all = "camelCase")]
-struct MemberPayload {
+#[schema(as = Member)]
+pub(super) struct MemberPayload {
     // id: Option<String>, // deprecated
-    clock: Option<i64>,
-    network_id: Option<String>,
-    node_id: Option<String>,
+    pub clock: Option<i64>,
+    pub network_id: Option<String>,
+    pub node_id: Option<String>,
     // controller_id: Option<String>, // deprecated
-    hidden: Option<bool>,
-    name: Option<String>,
-    description: Option<String>,
-    config: Option<Map<String, Value>>,
+    pub hidden: Option<bool>,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub config: Option<Map<String, Value>>,
     // last_online: Option<i64>, // deprecated
-    last_seen: Option<i64>,
-    physical_address: Option<String>,
-    client_version: Option<String>,
-    protocol_version: Option<i32>,
-    supports_rule_engine: Option<bool>,
+    pub last_seen: Option<i64>,
+    pub online: Option<bool>,
+    pub physical_address: Option<String>,
+    pub client_version: Option<String>,
+    pub protocol_version: Option<i32>,
+    pub supports_rule_engine: Option<bool>,
 }
 
 impl MemberPayload {
+    /// Changes with the controller config and the metadata set by clients.
+    pub fn etag(&self) -> String {
+        etag::compute(self.config.as_ref(), &self.metadata())
+    }
+
+    /// The fields set by clients, stored in the sidecar.
+    pub(super) fn metadata(&self) -> Value {
+        json!({
+            "hidden": self.hidden,
+            "name": self.name,
+            "description": self.description,
+        })
+    }
+
     fn combine_from_file(
         config: Map<String, Value>,
         network_id: &str,
@@ -209,7 +428,10 @@ impl MemberPayload {
 
         // fetch from api
         if let Some(address) = self.node_id.as_deref() {
+            self.online = Some(false);
             if let Ok(peer) = ctx.get_peer(address).await {
+                self.online = Some(peer::is_online(&peer, ctx.online_threshold()));
+
                 if let Some(preferred_path) = peer
                     .get("paths")
                     .and_then(|x| x.as_array())
@@ -241,27 +463,326 @@ impl MemberPayload {
         Ok(())
     }
 
-    fn read_from_file(file_path: &std::path::Path) -> Result<Self> {
+    fn authorized(&self) -> bool {
+        self.config
+            .as_ref()
+            .and_then(|c| c.get("authorized"))
+            .and_then(|a| a.as_bool())
+            .unwrap_or_default()
+    }
+
+    fn is_online(&self) -> bool {
+        self.online.unwrap_or_default()
+    }
+
+    fn ip_assignments(&self) -> impl Iterator<Item = &str> {
+        self.config
+            .as_ref()
+            .and_then(|c| c.get("ipAssignments"))
+            .and_then(|x| x.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|x| x.as_str())
+    }
+
+    /// `[id, value]` pairs of `config.tags`.
+    fn tags(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.config
+            .as_ref()
+            .and_then(|c| c.get("tags"))
+            .and_then(|x| x.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|t| match t.as_array()?.as_slice() {
+                [id, value] => Some((id.as_u64()?, value.as_u64()?)),
+                _ => None,
+            })
+    }
+
+    pub(super) fn read_from_file(file_path: &std::path::Path) -> Result<Self> {
         let file = std::fs::File::open(file_path)?;
         let network = serde_json::from_reader(file)?;
         Ok(network)
     }
 
-    fn write_to_file(&self, file_path: &std::path::Path) -> Result<()> {
-        if let Some(dir) = file_path.parent() {
-            std::fs::create_dir_all(dir)?;
+    pub(super) fn write_to_file(&self, file_path: &std::path::Path) -> Result<()> {
+        write_sidecar(file_path, self, &NOT_STORED)
+    }
+}
+
+/// Criteria used to select members of a network.
+#[derive(Debug, Deserialize, Default, ToSchema, IntoParams)]
+#[serde(rename_all = "camelCase")]
+#[into_params(parameter_in = Query)]
+pub(super) struct MemberFilter {
+    /// match on `config.authorized`.
+    pub authorized: Option<bool>,
+    /// match on whether the member is online.
+    pub online: Option<bool>,
+    /// case-insensitive substring of the name or description.
+    pub name: Option<String>,
+    /// prefix of one of the assigned addresses, e.g. `10.1.`.
+    pub ip: Option<String>,
+    /// prefix of the client version, e.g. `1.12`.
+    pub version: Option<String>,
+    /// `id=value`, or `id` to match any value of the tag.
+    pub tag: Option<String>,
+}
+
+impl MemberFilter {
+    pub fn matches(&self, member: &MemberPayload) -> bool {
+        if let Some(authorized) = self.authorized {
+            if member.authorized() != authorized {
+                return false;
+            }
         }
-        let file = std::fs::File::options()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(file_path)?;
-        serde_json::to_writer(file, &self)?;
+
+        if let Some(online) = self.online {
+            if member.is_online() != online {
+                return false;
+            }
+        }
+
+        if let Some(name) = self.name.as_deref() {
+            let name = name.to_lowercase();
+            let found = [member.name.as_deref(), member.description.as_deref()]
+                .into_iter()
+                .flatten()
+                .any(|s| s.to_lowercase().contains(&name));
+            if !found {
+                return false;
+            }
+        }
+
+        if let Some(ip) = self.ip.as_deref() {
+            if !member.ip_assignments().any(|a| a.starts_with(ip)) {
+                return false;
+            }
+        }
+
+        if let Some(version) = self.version.as_deref() {
+            let matched = member.client_version.as_deref().is_some_and(|v| {
+                v.strip_prefix(version)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+            });
+            if !matched {
+                return false;
+            }
+        }
+
+        if let Some(tag) = self.tag.as_deref() {
+            let (id, value) = match tag.split_once('=') {
+                Some((id, value)) => (id, Some(value)),
+                None => (tag, None),
+            };
+            let matched = member.tags().any(|(i, v)| {
+                i.to_string() == id.trim() && value.is_none_or(|x| v.to_string() == x.trim())
+            });
+            if !matched {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Sorting and cursor pagination of a member listing.
+#[derive(Debug, Deserialize, Default, IntoParams)]
+#[serde(rename_all = "camelCase")]
+#[into_params(parameter_in = Query)]
+struct MemberPage {
+    /// one of `nodeId`, `name`, `authorized`, `online`, `lastSeen`, `ip` and
+    /// `clientVersion`; prefix with `-` for descending order.
+    sort: Option<String>,
+    /// the `X-Next-Cursor` of the previous page, with the same sort.
+    cursor: Option<String>,
+    /// at most 1000.
+    limit: Option<usize>,
+}
+
+const SORT_KEYS: [&str; 7] = [
+    "nodeId",
+    "name",
+    "authorized",
+    "online",
+    "lastSeen",
+    "ip",
+    "clientVersion",
+];
+
+const MAX_LIMIT: usize = 1000;
+
+impl MemberPage {
+    /// The sort key and whether the order is descending.
+    fn key(&self) -> Result<(&str, bool)> {
+        let sort = self.sort.as_deref().unwrap_or("nodeId");
+        let (key, descending) = match sort.strip_prefix('-') {
+            Some(key) => (key, true),
+            None => (sort, false),
+        };
+        match SORT_KEYS.contains(&key) {
+            true => Ok((key, descending)),
+            false => Err(ApiError::invalid("sort", format!("unknown key `{key}`"))),
+        }
+    }
+
+    fn sort(&self, members: &mut [MemberPayload]) -> Result<()> {
+        let (key, descending) = self.key()?;
+        // ties are broken by node id so that cursors stay stable.
+        members.sort_by_cached_key(|m| SortPosition::of(key, descending, m));
         Ok(())
     }
+
+    /// The page after the cursor, which holds the sort value and node id of
+    /// the last member of the previous page, and the cursor of the next page.
+    fn paginate(
+        &self,
+        mut members: Vec<MemberPayload>,
+    ) -> Result<(Vec<MemberPayload>, Option<String>)> {
+        let (key, descending) = self.key()?;
+        let sort = self.sort.as_deref().unwrap_or("nodeId");
+        if let Some(limit) = self.limit.filter(|l| !(1..=MAX_LIMIT).contains(l)) {
+            return Err(ApiError::invalid(
+                "limit",
+                format!("{limit} is not between 1 and {MAX_LIMIT}"),
+            ));
+        }
+
+        if let Some(cursor) = self.cursor.as_deref() {
+            let cursor = SortPosition::decode(sort, descending, cursor).ok_or_else(|| {
+                ApiError::invalid("cursor", "not a cursor of a listing with this sort")
+            })?;
+            // members deleted or changed since are skipped by their position.
+            let start = members
+                .iter()
+                .position(|m| SortPosition::of(key, descending, m) > cursor)
+                .unwrap_or(members.len());
+            members.drain(..start);
+        }
+
+        let next_cursor = match self.limit {
+            Some(limit) if members.len() > limit => {
+                members.truncate(limit);
+                members
+                    .last()
+                    .map(|m| SortPosition::of(key, descending, m).encode(sort))
+            }
+            _ => None,
+        };
+
+        Ok((members, next_cursor))
+    }
+}
+
+/// Where a member is in a listing: its sort value, then its node id.
+#[derive(Debug, PartialEq, Eq)]
+struct SortPosition {
+    value: Value,
+    node_id: Option<String>,
+    descending: bool,
+}
+
+impl SortPosition {
+    fn of(key: &str, descending: bool, member: &MemberPayload) -> Self {
+        let value = match key {
+            "nodeId" => json!(member.node_id),
+            "name" => json!(member.name.as_deref().map(str::to_lowercase)),
+            "authorized" => json!(member.authorized()),
+            "online" => json!(member.is_online()),
+            "lastSeen" => json!(member.last_seen),
+            // ipv4 before ipv6, then by octets.
+            "ip" => json!(member
+                .ip_assignments()
+                .filter_map(|s| s.parse::<IpAddr>().ok())
+                .min()
+                .map(|ip| match ip {
+                    IpAddr::V4(ip) => [&[4][..], &ip.octets()].concat(),
+                    IpAddr::V6(ip) => [&[6][..], &ip.octets()].concat(),
+                })),
+            "clientVersion" => json!(member.client_version.as_deref().map(|v| {
+                v.split('.')
+                    .map(|x| x.parse::<u32>().unwrap_or_default())
+                    .collect::<Vec<_>>()
+            })),
+            _ => Value::Null,
+        };
+        Self {
+            value,
+            node_id: member.node_id.clone(),
+            descending,
+        }
+    }
+
+    /// Hex of `[sort, value, nodeId]`, safe in a query string.
+    fn encode(&self, sort: &str) -> String {
+        json!([sort, self.value, self.node_id])
+            .to_string()
+            .bytes()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    /// `None` unless the cursor is of a listing with the same `sort`.
+    fn decode(sort: &str, descending: bool, cursor: &str) -> Option<Self> {
+        let bytes = (0..cursor.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(cursor.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<_>>>()?;
+        let (cursor_sort, value, node_id) =
+            serde_json::from_slice::<(String, Value, Option<String>)>(&bytes).ok()?;
+        if cursor_sort != sort {
+            return None;
+        }
+        Some(Self {
+            value,
+            node_id,
+            descending,
+        })
+    }
+}
+
+impl PartialOrd for SortPosition {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SortPosition {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let ordering = compare_values(&self.value, &other.value);
+        let ordering = match self.descending {
+            true => ordering.reverse(),
+            false => ordering,
+        };
+        ordering.then_with(|| self.node_id.cmp(&other.node_id))
+    }
+}
+
+/// Orders the sort values of one key, unset ones first.
+fn compare_values(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Null, Value::Null) => Ordering::Equal,
+        (Value::Null, _) => Ordering::Less,
+        (_, Value::Null) => Ordering::Greater,
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => a.as_i64().cmp(&b.as_i64()),
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Array(a), Value::Array(b)) => a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| compare_values(a, b))
+            .find(|o| o.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        _ => Ordering::Equal,
+    }
 }
 
-fn member_file_path(work_dir: &std::path::Path, network_id: &str, member_id: &str) -> PathBuf {
+pub(super) fn member_file_path(
+    work_dir: &std::path::Path,
+    network_id: &str,
+    member_id: &str,
+) -> PathBuf {
     work_dir
         .join("controller.d")
         .join("network")
@@ -269,3 +790,156 @@ fn member_file_path(work_dir: &std::path::Path, network_id: &str, member_id: &st
         .join("member")
         .join(format!("{}.ext.json", member_id))
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{member_file_path, MemberFilter, MemberPage, MemberPayload};
+
+    fn member(node_id: &str, version: &str, tags: serde_json::Value) -> MemberPayload {
+        MemberPayload {
+            node_id: Some(node_id.to_string()),
+            client_version: Some(version.to_string()),
+            config: serde_json::from_value(json!({ "tags": tags })).ok(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_member_filter_and_page() {
+        let members = vec![
+            member("c", "1.10.6", json!([[1, 2]])),
+            member("a", "1.12.2", json!([[1, 3]])),
+            member("b", "1.1.0", json!([])),
+        ];
+
+        let filter = MemberFilter {
+            version: Some("1.1".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(members.iter().filter(|m| filter.matches(m)).count(), 1);
+
+        let filter = MemberFilter {
+            tag: Some("1".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(members.iter().filter(|m| filter.matches(m)).count(), 2);
+
+        let page = MemberPage {
+            sort: Some("-clientVersion".to_string()),
+            cursor: None,
+            limit: Some(2),
+        };
+        let mut members = members;
+        page.sort(&mut members).unwrap();
+        let (first, cursor) = page.paginate(members).unwrap();
+        let ids = first
+            .iter()
+            .flat_map(|m| m.node_id.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["a", "c"]);
+
+        // the cursor member was deleted
+        let page = MemberPage { cursor, ..page };
+        let mut members = vec![
+            member("a", "1.12.2", json!([])),
+            member("b", "1.1.0", json!([])),
+        ];
+        page.sort(&mut members).unwrap();
+        let (next, cursor) = page.paginate(members).unwrap();
+        let ids = next
+            .iter()
+            .flat_map(|m| m.node_id.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["b"]);
+        assert!(cursor.is_none());
+    }
+
+    #[test]
+    fn test_member_page_by_name() {
+        // sorted by name, without `deleted`
+        let members = |deleted: &str| {
+            [
+                ("a", "Bravo"),
+                ("b", "delta"),
+                ("c", "charlie"),
+                ("d", "alpha"),
+            ]
+            .into_iter()
+            .filter(|(node_id, _)| *node_id != deleted)
+            .map(|(node_id, name)| MemberPayload {
+                node_id: Some(node_id.to_string()),
+                name: Some(name.to_string()),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>()
+        };
+        let ids = |members: &[MemberPayload]| {
+            members
+                .iter()
+                .flat_map(|m| m.node_id.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let page = MemberPage {
+            sort: Some("name".to_string()),
+            cursor: None,
+            limit: Some(2),
+        };
+        let mut all = members("");
+        page.sort(&mut all).unwrap();
+        let (first, cursor) = page.paginate(all).unwrap();
+        assert_eq!(ids(&first), ["d", "a"]);
+
+        // the cursor member was deleted, the next page still starts after it.
+        let page = MemberPage { cursor, ..page };
+        let mut rest = members("a");
+        page.sort(&mut rest).unwrap();
+        let (next, cursor) = page.paginate(rest).unwrap();
+        assert_eq!(ids(&next), ["c", "b"]);
+        assert!(cursor.is_none());
+
+        let page = MemberPage {
+            cursor: Some("zz".to_string()),
+            ..page
+        };
+        assert!(page.paginate(members("")).is_err());
+        let page = MemberPage {
+            cursor: None,
+            limit: Some(0),
+            ..page
+        };
+        assert!(page.paginate(members("")).is_err());
+    }
+
+    #[test]
+    fn test_sidecar_without_computed_fields() {
+        let dir = std::env::temp_dir().join(format!("zt-edge-member-{}", std::process::id()));
+        let file_path = member_file_path(&dir, "8056c2e21c000001", "0123456789");
+        let member = MemberPayload {
+            name: Some("laptop".to_string()),
+            clock: Some(1700000000000),
+            online: Some(true),
+            last_seen: Some(1700000000000),
+            physical_address: Some("192.0.2.1".to_string()),
+            client_version: Some("1.14.0".to_string()),
+            protocol_version: Some(13),
+            config: serde_json::from_value(json!({ "authorized": true })).ok(),
+            ..Default::default()
+        };
+        member.write_to_file(&file_path).unwrap();
+
+        let stored: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&file_path).unwrap()).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        let keys = stored
+            .as_object()
+            .unwrap()
+            .iter()
+            .filter(|(_, v)| !v.is_null())
+            .map(|(k, _)| k.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["name"]);
+    }
+}
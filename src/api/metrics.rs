@@ -0,0 +1,532 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header::CONTENT_TYPE, HeaderMap},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use futures::future::join_all;
+
+use super::{
+    ctx::Ctx,
+    network::{list_networks, NetworkPalyload},
+    peer::{self, PeerSample},
+    Result, SharedState,
+};
+use crate::log;
+
+/// Metrics collected while serving, exported together with the controller
+/// state at `/metrics`.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// by method and endpoint.
+    controller_durations: Mutex<BTreeMap<(String, String), Histogram>>,
+    /// by method, endpoint and reason.
+    controller_errors: Mutex<BTreeMap<(String, String, String), u64>>,
+    /// by method, route and status.
+    http_requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    /// by method and route.
+    http_durations: Mutex<BTreeMap<(String, String), Histogram>>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, le) in self.buckets.iter_mut().zip(BUCKETS) {
+            if value <= le {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+impl Metrics {
+    /// Records a request to the controller, `path` is normalized so that ids
+    /// don't end up in the labels.
+    pub fn observe_controller(
+        &self,
+        method: &str,
+        path: &str,
+        elapsed: Duration,
+        result: &reqwest::Result<reqwest::Response>,
+    ) {
+        let endpoint = normalize_path(path);
+
+        if let Ok(mut durations) = self.controller_durations.lock() {
+            durations
+                .entry((method.to_string(), endpoint.clone()))
+                .or_default()
+                .observe(elapsed.as_secs_f64());
+        }
+
+        if let Err(err) = result {
+            let reason = match err.status() {
+                Some(status) => status.as_u16().to_string(),
+                None if err.is_timeout() => "timeout".to_string(),
+                None if err.is_connect() => "connect".to_string(),
+                None => "other".to_string(),
+            };
+            if let Ok(mut errors) = self.controller_errors.lock() {
+                *errors
+                    .entry((method.to_string(), endpoint, reason))
+                    .or_default() += 1;
+            }
+        }
+    }
+
+    fn observe_http(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        if let Ok(mut requests) = self.http_requests.lock() {
+            *requests
+                .entry((method.to_string(), route.to_string(), status))
+                .or_default() += 1;
+        }
+        if let Ok(mut durations) = self.http_durations.lock() {
+            durations
+                .entry((method.to_string(), route.to_string()))
+                .or_default()
+                .observe(elapsed.as_secs_f64());
+        }
+    }
+
+    fn encode(&self, out: &mut Encoder) {
+        if let Ok(durations) = self.controller_durations.lock() {
+            out.family(
+                "zerotier_edge_controller_request_duration_seconds",
+                "Latency of requests to the controller.",
+                "histogram",
+            );
+            for ((method, endpoint), histogram) in durations.iter() {
+                out.histogram(
+                    "zerotier_edge_controller_request_duration_seconds",
+                    &[("method", method), ("endpoint", endpoint)],
+                    histogram,
+                );
+            }
+        }
+
+        if let Ok(errors) = self.controller_errors.lock() {
+            out.family(
+                "zerotier_edge_controller_request_errors_total",
+                "Failed requests to the controller.",
+                "counter",
+            );
+            for ((method, endpoint, reason), count) in errors.iter() {
+                out.sample(
+                    "zerotier_edge_controller_request_errors_total",
+                    &[
+                        ("method", method),
+                        ("endpoint", endpoint),
+                        ("reason", reason),
+                    ],
+                    *count as f64,
+                );
+            }
+        }
+
+        if let Ok(requests) = self.http_requests.lock() {
+            out.family(
+                "zerotier_edge_http_requests_total",
+                "Requests served by the api.",
+                "counter",
+            );
+            for ((method, route, status), count) in requests.iter() {
+                out.sample(
+                    "zerotier_edge_http_requests_total",
+                    &[
+                        ("method", method),
+                        ("route", route),
+                        ("status", &status.to_string()),
+                    ],
+                    *count as f64,
+                );
+            }
+        }
+
+        if let Ok(durations) = self.http_durations.lock() {
+            out.family(
+                "zerotier_edge_http_request_duration_seconds",
+                "Latency of requests served by the api.",
+                "histogram",
+            );
+            for ((method, route), histogram) in durations.iter() {
+                out.histogram(
+                    "zerotier_edge_http_request_duration_seconds",
+                    &[("method", method), ("route", route)],
+                    histogram,
+                );
+            }
+        }
+    }
+}
+
+/// Middleware recording the requests served by the api.
+pub async fn track_http(request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_default();
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    METRICS.observe_http(&method, &route, response.status().as_u16(), start.elapsed());
+    response
+}
+
+/// The metrics of every controller, labelled with its name. Needs a token
+/// accepted by the default controller, e.g. the `auth_token` as bearer token.
+pub async fn get_metrics(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse> {
+    Ctx::for_request(&state, &headers, state.default_controller().clone())
+        .authorize()
+        .await?;
+
+    let controllers = join_all(state.controllers.iter().map(|controller| {
+        let state = &state;
+        async move {
+            let result = match Ctx::background_on(state, controller) {
+                Some(ctx) => collect_controller(&ctx).await.map(Some),
+                None => Ok(None),
+            };
+            match result {
+                Ok(metrics) => (controller.name.as_str(), metrics),
+                Err(err) => {
+                    log::warn!(
+                        "failed to collect metrics of controller {}: {}",
+                        controller.name,
+                        err
+                    );
+                    (controller.name.as_str(), None)
+                }
+            }
+        }
+    }))
+    .await;
+
+    let mut out = Encoder::default();
+    out.family(
+        "zerotier_edge_controller_up",
+        "Whether the controller state could be collected, requires a token accepted by the controller before.",
+        "gauge",
+    );
+    for (name, metrics) in &controllers {
+        out.sample(
+            "zerotier_edge_controller_up",
+            &[("controller", name)],
+            metrics.is_some() as u8 as f64,
+        );
+    }
+
+    let controllers = controllers
+        .iter()
+        .filter_map(|(name, metrics)| Some((*name, metrics.as_ref()?)))
+        .collect::<Vec<_>>();
+    encode_controllers(&mut out, &controllers);
+
+    if let Some(report) = state.replication.as_ref().and_then(|r| r.report()) {
+        out.family(
+            "zerotier_edge_replication_drift",
+            "Differences of the standby found by the last replication.",
+            "gauge",
+        );
+        out.sample(
+            "zerotier_edge_replication_drift",
+            &[],
+            report.drift_count() as f64,
+        );
+
+        out.family(
+            "zerotier_edge_replication_success",
+            "Whether the last replication finished without error.",
+            "gauge",
+        );
+        out.sample(
+            "zerotier_edge_replication_success",
+            &[],
+            report.succeeded() as u8 as f64,
+        );
+
+        out.family(
+            "zerotier_edge_replication_finished_timestamp_seconds",
+            "End of the last replication.",
+            "gauge",
+        );
+        out.sample(
+            "zerotier_edge_replication_finished_timestamp_seconds",
+            &[],
+            report.finished_at() as f64 / 1000.0,
+        );
+    }
+
+    METRICS.encode(&mut out);
+
+    Ok((
+        [(CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        out.0,
+    ))
+}
+
+/// The state of a controller the metrics are made of.
+struct ControllerMetrics {
+    networks: Vec<NetworkPalyload>,
+    /// address, role and sample of every peer.
+    peers: Vec<(String, String, PeerSample)>,
+    online_peers: usize,
+}
+
+async fn collect_controller(ctx: &Ctx) -> Result<ControllerMetrics> {
+    let networks = list_networks(ctx).await?;
+    let online_peers = peer::online_addresses(ctx).await?.len();
+
+    let now = super::history::now_millis();
+    let peers = ctx
+        .get_peers()
+        .await?
+        .iter()
+        .filter_map(|p| {
+            let address = p.get("address")?.as_str()?.to_string();
+            let role = p.get("role").and_then(|x| x.as_str()).unwrap_or_default();
+            Some((address, role.to_string(), PeerSample::from_peer(p, now)))
+        })
+        .collect();
+
+    Ok(ControllerMetrics {
+        networks,
+        peers,
+        online_peers,
+    })
+}
+
+fn encode_controllers(out: &mut Encoder, controllers: &[(&str, &ControllerMetrics)]) {
+    out.family("zerotier_edge_networks", "Number of networks.", "gauge");
+    for (name, metrics) in controllers {
+        out.sample(
+            "zerotier_edge_networks",
+            &[("controller", name)],
+            metrics.networks.len() as f64,
+        );
+    }
+
+    type MemberCount = fn(&NetworkPalyload) -> Option<usize>;
+    let gauges: [(&str, &str, MemberCount); 3] = [
+        (
+            "zerotier_edge_network_members",
+            "Number of members of a network.",
+            |n| n.total_member_count,
+        ),
+        (
+            "zerotier_edge_network_members_authorized",
+            "Number of authorized members of a network.",
+            |n| n.authorized_member_count,
+        ),
+        (
+            "zerotier_edge_network_members_online",
+            "Number of online members of a network.",
+            |n| n.online_member_count,
+        ),
+    ];
+
+    for (family, help, count) in gauges {
+        out.family(family, help, "gauge");
+        for (name, metrics) in controllers {
+            for network in &metrics.networks {
+                let network_id = network.id.as_deref().unwrap_or_default();
+                let network_name = network
+                    .config
+                    .as_ref()
+                    .and_then(|c| c.get("name"))
+                    .and_then(|x| x.as_str())
+                    .unwrap_or_default();
+                out.sample(
+                    family,
+                    &[
+                        ("controller", name),
+                        ("network_id", network_id),
+                        ("network_name", network_name),
+                    ],
+                    count(network).unwrap_or_default() as f64,
+                );
+            }
+        }
+    }
+
+    out.family("zerotier_edge_peers", "Number of peers.", "gauge");
+    for (name, metrics) in controllers {
+        out.sample(
+            "zerotier_edge_peers",
+            &[("controller", name)],
+            metrics.peers.len() as f64,
+        );
+    }
+
+    out.family(
+        "zerotier_edge_peers_online",
+        "Number of peers that sent a packet recently.",
+        "gauge",
+    );
+    for (name, metrics) in controllers {
+        out.sample(
+            "zerotier_edge_peers_online",
+            &[("controller", name)],
+            metrics.online_peers as f64,
+        );
+    }
+
+    out.family(
+        "zerotier_edge_peers_relayed",
+        "Number of peers without a direct path.",
+        "gauge",
+    );
+    for (name, metrics) in controllers {
+        out.sample(
+            "zerotier_edge_peers_relayed",
+            &[("controller", name)],
+            metrics.peers.iter().filter(|(_, _, s)| s.relayed).count() as f64,
+        );
+    }
+
+    out.family(
+        "zerotier_edge_peer_latency_seconds",
+        "Round trip time to a peer.",
+        "gauge",
+    );
+    for (name, metrics) in controllers {
+        for (address, role, sample) in &metrics.peers {
+            if let Some(latency) = sample.latency {
+                out.sample(
+                    "zerotier_edge_peer_latency_seconds",
+                    &[("controller", name), ("address", address), ("role", role)],
+                    latency as f64 / 1000.0,
+                );
+            }
+        }
+    }
+
+    out.family(
+        "zerotier_edge_peer_relayed",
+        "Whether the traffic to a peer is relayed.",
+        "gauge",
+    );
+    for (name, metrics) in controllers {
+        for (address, role, sample) in &metrics.peers {
+            out.sample(
+                "zerotier_edge_peer_relayed",
+                &[("controller", name), ("address", address), ("role", role)],
+                sample.relayed as u8 as f64,
+            );
+        }
+    }
+}
+
+/// Prometheus text exposition format.
+#[derive(Default)]
+struct Encoder(String);
+
+impl Encoder {
+    fn family(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.0, "# HELP {name} {help}");
+        let _ = writeln!(self.0, "# TYPE {name} {kind}");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            self.0.push('{');
+            for (i, (key, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.0.push(',');
+                }
+                let value = value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                let _ = write!(self.0, "{key}=\"{value}\"");
+            }
+            self.0.push('}');
+        }
+        let _ = writeln!(self.0, " {value}");
+    }
+
+    fn histogram(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+        let bucket = format!("{name}_bucket");
+        for (count, le) in histogram.buckets.iter().zip(BUCKETS) {
+            let le = le.to_string();
+            let labels = [labels, &[("le", le.as_str())]].concat();
+            self.sample(&bucket, &labels, *count as f64);
+        }
+        let labels_inf = [labels, &[("le", "+Inf")]].concat();
+        self.sample(&bucket, &labels_inf, histogram.count as f64);
+        self.sample(&format!("{name}_sum"), labels, histogram.sum);
+        self.sample(&format!("{name}_count"), labels, histogram.count as f64);
+    }
+}
+
+/// Replaces the ids in a controller path, e.g. `/controller/network/:network_id`.
+fn normalize_path(path: &str) -> String {
+    let mut previous = "";
+    path.split('/')
+        .map(|segment| {
+            let normalized = match previous {
+                "network" => ":network_id",
+                "member" => ":member_id",
+                "peer" => ":address",
+                _ => segment,
+            };
+            previous = segment;
+            normalized
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_path, Encoder, Histogram};
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(
+            normalize_path("/controller/network/8056c2e21c000001/member/1a2b3c4d5e"),
+            "/controller/network/:network_id/member/:member_id"
+        );
+        assert_eq!(normalize_path("/status"), "/status");
+
+        let mut histogram = Histogram::default();
+        histogram.observe(0.02);
+        histogram.observe(3.0);
+
+        let mut out = Encoder::default();
+        out.sample("up", &[("name", "a\"b")], 1.0);
+        out.histogram("d", &[("m", "GET")], &histogram);
+
+        assert!(out.0.contains("up{name=\"a\\\"b\"} 1\n"));
+        assert!(out.0.contains("d_bucket{m=\"GET\",le=\"0.01\"} 0\n"));
+        assert!(out.0.contains("d_bucket{m=\"GET\",le=\"0.025\"} 1\n"));
+        assert!(out.0.contains("d_bucket{m=\"GET\",le=\"+Inf\"} 2\n"));
+        assert!(out.0.contains("d_count{m=\"GET\"} 2\n"));
+    }
+}
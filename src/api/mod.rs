@@ -1,25 +1,53 @@
-use std::{io, path::PathBuf, sync::Arc};
+use std::{io, net::IpAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use axum::{
     http::{Error as HttpError, StatusCode},
+    middleware,
     response::{IntoResponse, Response},
     routing::get,
-    Json, Router,
+    Router,
 };
 
-use serde::{de::DeserializeOwned, ser::Serialize};
-use serde_json::{json, Value};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
 use thiserror::Error;
+use utoipa::ToSchema;
 
 use reqwest::Client;
+use tokio::sync::{Mutex, Semaphore};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
+use crate::log;
+
+mod bulk;
+mod cache;
+mod central;
+pub mod cli;
 mod ctx;
+pub mod doctor;
+mod etag;
+mod extract;
+mod fleet;
+mod health;
+mod history;
 mod member;
+mod metrics;
 mod network;
+mod openapi;
+mod patch;
 mod peer;
+mod presence;
+mod replication;
+mod request_id;
+mod template;
+mod token;
 mod zt;
 
+pub use cache::Cache;
 use ctx::Ctx;
+use extract::Json;
+pub use replication::Replication;
+pub use token::TokenFile;
 
 type SharedState = Arc<ApiState>;
 
@@ -27,22 +55,133 @@ type Result<T> = std::result::Result<T, ApiError>;
 
 #[derive(Debug)]
 pub struct ApiState {
+    /// the default controller first, the one served by `/api/v1` itself.
+    pub controllers: Vec<Arc<ControllerState>>,
+    /// requests with this token use the token file of the default controller.
+    pub auth_token: Option<String>,
+    /// requests without a token use the token file of the default controller.
+    pub allow_anonymous: bool,
+    /// retries of idempotent requests to the controller.
+    pub retries: u32,
+    /// members whose peer received a packet within this window are online.
+    pub online_threshold: Duration,
+    /// interval of the history sampler, zero disables it.
+    pub sample_interval: Duration,
+    /// how long the presence history is kept.
+    pub history_retention: Duration,
+    /// how long the peer latency history is kept.
+    pub peer_history_retention: Duration,
+    /// copies a primary controller to a standby one, if configured.
+    pub replication: Option<Replication>,
+    /// cancelled on shutdown, background tasks stop at their next pause.
+    pub shutdown: CancellationToken,
+    /// background tasks to wait for on shutdown.
+    pub tasks: TaskTracker,
+}
+
+impl ApiState {
+    pub fn default_controller(&self) -> &Arc<ControllerState> {
+        &self.controllers[0]
+    }
+
+    pub fn controller(&self, name: &str) -> Option<&Arc<ControllerState>> {
+        self.controllers.iter().find(|c| c.name == name)
+    }
+}
+
+/// A controller managed by this instance, with its own connection, token,
+/// cache and files.
+#[derive(Debug)]
+pub struct ControllerState {
+    pub name: String,
     pub api: String,
+    /// sidecar files and history, the work dir for the default controller.
     pub work_dir: PathBuf,
+    /// token from the token file or authtoken.secret.
+    pub token: TokenFile,
     pub client: Client,
+    /// bounds the number of requests in flight to the controller.
+    pub limiter: Semaphore,
+    pub cache: Cache,
+    /// held by updates that read the current state first, until their write.
+    pub update_lock: Mutex<()>,
+}
+
+/// Starts the background tasks that work on the shared state.
+pub fn spawn_tasks(state: &SharedState) {
+    for controller in &state.controllers {
+        cache::spawn_refresh(state.clone(), controller.clone());
+        history::spawn_sampler(state.clone(), controller.clone());
+        token::spawn_reload(state.clone(), controller.clone());
+    }
+    replication::spawn_replication(state.clone());
+}
+
+/// Waits for the background tasks to finish their current work after
+/// shutdown, returns false on timeout.
+pub async fn wait_tasks(state: &SharedState, timeout: Duration) -> bool {
+    state.tasks.close();
+    tokio::time::timeout(timeout, state.tasks.wait())
+        .await
+        .is_ok()
+}
+
+/// Addresses assigned to this node in `network_id`, or in every joined network.
+pub async fn managed_addresses(
+    state: &SharedState,
+    network_id: Option<&str>,
+) -> Result<Vec<IpAddr>> {
+    let ctx = Ctx::background(state).ok_or(ApiError::Unauthorized)?;
+    let networks = ctx.get_joined_networks().await?;
+    Ok(networks
+        .iter()
+        .filter(|n| network_id.is_none_or(|id| n.get("id").and_then(Value::as_str) == Some(id)))
+        .filter_map(|n| n.get("assignedAddresses")?.as_array())
+        .flatten()
+        .filter_map(|a| a.as_str()?.split('/').next()?.parse().ok())
+        .collect())
 }
 
 pub fn routes() -> Router<SharedState> {
-    Router::new().nest(
-        "/api/v1",
-        Router::new()
-            .route("/status", get(status))
-            .merge(network::routes())
-            .merge(member::routes())
-            .merge(peer::routes()),
-    )
+    Router::new()
+        .nest(
+            "/api/v1",
+            v1_routes()
+                .merge(fleet::routes())
+                .merge(replication::routes()),
+        )
+        .nest(
+            "/central/api/v1",
+            central::routes().route_layer(middleware::from_fn(metrics::track_http)),
+        )
+        .route("/metrics", get(metrics::get_metrics))
+        .route("/healthz", get(health::get_healthz))
+        .route("/readyz", get(health::get_readyz))
+        .layer(middleware::from_fn(request_id::propagate))
+}
+
+/// The routes of `/api/v1`, served for the default controller and under
+/// `/api/v1/controllers/{name}` for every controller.
+fn v1_routes() -> Router<SharedState> {
+    Router::new()
+        .route("/status", get(status))
+        .merge(network::routes())
+        .merge(member::routes())
+        .merge(bulk::routes())
+        .merge(peer::routes())
+        .merge(presence::routes())
+        .merge(template::routes())
+        .merge(openapi::routes())
+        .route_layer(middleware::from_fn(metrics::track_http))
 }
 
+/// Status of the zerotier-one node.
+#[utoipa::path(
+    get,
+    path = "/status",
+    tag = "status",
+    responses((status = 200, body = Object)),
+)]
 async fn status(ctx: Ctx) -> Result<Json<Value>> {
     Ok(Json(ctx.get_status().await?))
 }
@@ -51,12 +190,20 @@ async fn status(ctx: Ctx) -> Result<Json<Value>> {
 pub enum ApiError {
     #[error("io error {0}")]
     Io(#[from] io::Error),
+    #[error("sidecar file {0:?}: {1}")]
+    Sidecar(PathBuf, #[source] io::Error),
     #[error("http error {0}")]
     Http(#[from] HttpError),
     #[error("serde json error {0}")]
     SerdeJson(#[from] serde_json::Error),
     #[error("http client error {0}")]
     HttpClient(reqwest::Error),
+    #[error("zerotier controller timed out {0}")]
+    ControllerTimeout(reqwest::Error),
+    #[error("zerotier controller unreachable {0}")]
+    ControllerUnreachable(reqwest::Error),
+    #[error("zerotier controller rejected the request with {0}")]
+    ControllerRejected(reqwest::StatusCode),
     #[error("zerotier error {0}")]
     Zerotier(String),
     #[error("peer {0} not found error.")]
@@ -65,8 +212,22 @@ pub enum ApiError {
     MemberNotFound(String),
     #[error("network {0} not found error.")]
     NetworkNotFound(String),
+    #[error("controller {0} not found error.")]
+    ControllerNotFound(String),
+    #[error("template {0} not found error.")]
+    TemplateNotFound(String),
+    #[error("replication is not configured.")]
+    ReplicationNotConfigured,
     #[error("Unauthorized")]
     Unauthorized,
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    #[error("invalid fields: {}", fields_message(.0))]
+    InvalidFields(Vec<FieldError>),
+    #[error("precondition failed: {0}")]
+    PreconditionFailed(String),
+    #[error("unsupported media type, {0}")]
+    UnsupportedMediaType(String),
 }
 
 impl From<reqwest::Error> for ApiError {
@@ -76,39 +237,163 @@ impl From<reqwest::Error> for ApiError {
             if status == StatusCode::UNAUTHORIZED {
                 return ApiError::Unauthorized;
             }
+            if status.is_client_error() {
+                return ApiError::ControllerRejected(status);
+            }
+        }
+
+        if err.is_timeout() {
+            return ApiError::ControllerTimeout(err);
+        }
+        if err.is_connect() {
+            return ApiError::ControllerUnreachable(err);
         }
 
         ApiError::HttpClient(err)
     }
 }
 
-impl IntoResponse for ApiError {
-    fn into_response(self) -> Response {
-        // let (status, error_message) = match self {
-        //     AppError::UserRepo(UserRepoError::NotFound) => {
-        //         (StatusCode::NOT_FOUND, "User not found")
-        //     }
-        //     AppError::UserRepo(UserRepoError::InvalidUsername) => {
-        //         (StatusCode::UNPROCESSABLE_ENTITY, "Invalid username")
-        //     }
-        // };
-
-        let (status, error_message) = match self {
+impl ApiError {
+    /// Invalid `field`, for errors found by validation.
+    pub fn invalid(field: impl Into<String>, message: impl Into<String>) -> Self {
+        ApiError::InvalidFields(vec![FieldError {
+            field: field.into(),
+            message: message.into(),
+        }])
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
             ApiError::PeerNotFound(_)
             | ApiError::MemberNotFound(_)
-            | ApiError::NetworkNotFound(_) => (StatusCode::NOT_FOUND, self.to_string()),
-            ApiError::Unauthorized => (StatusCode::UNAUTHORIZED, self.to_string()),
-            _ => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-        };
+            | ApiError::NetworkNotFound(_)
+            | ApiError::ControllerNotFound(_)
+            | ApiError::TemplateNotFound(_)
+            | ApiError::ReplicationNotConfigured => StatusCode::NOT_FOUND,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::BadRequest(_) | ApiError::InvalidFields(_) => StatusCode::BAD_REQUEST,
+            ApiError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::ControllerRejected(status) => {
+                StatusCode::from_u16(status.as_u16()).unwrap_or(StatusCode::BAD_REQUEST)
+            }
+            ApiError::ControllerTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::ControllerUnreachable(_)
+            | ApiError::HttpClient(_)
+            | ApiError::Zerotier(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Io(_)
+            | ApiError::Sidecar(..)
+            | ApiError::Http(_)
+            | ApiError::SerdeJson(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable, machine-readable kind of the error.
+    pub(super) fn code(&self) -> &'static str {
+        match self {
+            ApiError::Io(_) | ApiError::Http(_) | ApiError::SerdeJson(_) => "internal_error",
+            ApiError::Sidecar(..) => "sidecar_io_error",
+            ApiError::HttpClient(_) | ApiError::Zerotier(_) => "controller_error",
+            ApiError::ControllerTimeout(_) => "controller_timeout",
+            ApiError::ControllerUnreachable(_) => "controller_unreachable",
+            ApiError::ControllerRejected(_) => "controller_rejected",
+            ApiError::PeerNotFound(_) => "peer_not_found",
+            ApiError::MemberNotFound(_) => "member_not_found",
+            ApiError::NetworkNotFound(_) => "network_not_found",
+            ApiError::ControllerNotFound(_) => "controller_not_found",
+            ApiError::TemplateNotFound(_) => "template_not_found",
+            ApiError::ReplicationNotConfigured => "replication_not_configured",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::InvalidFields(_) => "invalid_fields",
+            ApiError::PreconditionFailed(_) => "precondition_failed",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+        }
+    }
+}
 
-        let body = Json(json!({
-            "error": error_message,
-        }));
+fn fields_message(fields: &[FieldError]) -> String {
+    fields
+        .iter()
+        .map(|f| format!("`{}` {}", f.field, f.message))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let request_id = request_id::current();
+        if status.is_server_error() {
+            log::warn!(
+                "request {} failed: {}",
+                request_id.as_deref().unwrap_or("-"),
+                self
+            );
+        }
+
+        let body = Json(ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            fields: match self {
+                ApiError::InvalidFields(fields) => fields,
+                _ => vec![],
+            },
+            request_id,
+        });
 
         (status, body).into_response()
     }
 }
 
+/// Body of error responses.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct ErrorBody {
+    /// e.g. `member_not_found` or `controller_unreachable`.
+    code: &'static str,
+    message: String,
+    /// the invalid fields of the request, for `invalid_fields`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldError>,
+    /// also returned in `X-Request-Id`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FieldError {
+    /// path of the field, e.g. `members[0]`.
+    pub field: String,
+    pub message: String,
+}
+
+/// Writes the `.ext.json` sidecar of a network or member at `path`.
+/// Writes `value` to the sidecar at `path`, without the fields `not_stored`.
+fn write_sidecar(
+    path: &std::path::Path,
+    value: &impl Serialize,
+    not_stored: &[&str],
+) -> Result<()> {
+    let mut value = serde_json::to_value(value)?;
+    if let Some(value) = value.as_object_mut() {
+        value.retain(|key, _| !not_stored.contains(&key.as_str()));
+    }
+    let write = || {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = std::fs::File::options()
+            .write(true)
+            .truncate(true)
+            .create(true)
+            .open(path)?;
+        serde_json::to_writer(file, &value)?;
+        Ok(())
+    };
+    write().map_err(|err: io::Error| ApiError::Sidecar(path.to_path_buf(), err))
+}
+
 fn assign_not_none_to<T: Serialize + DeserializeOwned>(b: &T, a: T) -> Result<T> {
     let mut a = serde_json::to_value(&a)?;
     let b = serde_json::to_value(b)?;
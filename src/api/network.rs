@@ -1,17 +1,27 @@
-use std::path::PathBuf;
+use std::{collections::HashSet, path::PathBuf};
 
 use axum::{
     extract::Path,
-    routing::{delete, get, post},
-    Json, Router,
+    http::HeaderMap,
+    routing::{delete, get, patch, post},
+    Router,
 };
 
 use serde::{Deserialize, Serialize};
-use serde_json::{Map, Value};
-
-use futures::future::join_all;
-
-use super::{assign_not_none_to, ctx::Ctx, Result, SharedState};
+use serde_json::{json, Map, Value};
+use utoipa::ToSchema;
+
+use futures::{stream, StreamExt, TryStreamExt};
+
+use super::{
+    assign_not_none_to,
+    cache::FETCH_CONCURRENCY,
+    ctx::Ctx,
+    etag,
+    extract::Json,
+    patch::{changed_config, Patch},
+    peer, write_sidecar, ApiError, Result, SharedState,
+};
 
 #[inline]
 pub fn routes() -> Router<SharedState> {
@@ -20,73 +30,191 @@ pub fn routes() -> Router<SharedState> {
         .route("/network", post(create_network))
         .route("/network/:network_id", post(update_network))
         .route("/network/:network_id", get(get_network))
+        .route("/network/:network_id", patch(patch_network))
         .route("/network/:network_id", delete(delete_network))
 }
 
+/// Networks of the controller.
+#[utoipa::path(
+    get,
+    path = "/network",
+    tag = "network",
+    responses((status = 200, body = [NetworkPalyload])),
+)]
 async fn get_networks(ctx: Ctx) -> Result<Json<Vec<NetworkPalyload>>> {
-    let network_ids = ctx.get_network_ids().await?;
-
-    let tasks = network_ids
-        .iter()
-        .map(|network_id| ctx.get_network(network_id));
+    Ok(Json(list_networks(&ctx).await?))
+}
 
-    let network_configs = join_all(tasks)
+pub(super) async fn list_networks(ctx: &Ctx) -> Result<Vec<NetworkPalyload>> {
+    let network_ids = ctx.get_network_ids().await?;
+    // shared by every network of the listing.
+    let online_peers = &peer::online_addresses(ctx).await?;
+
+    stream::iter(network_ids)
+        .map(|network_id| async move {
+            let config = ctx.get_network(&network_id).await?;
+            let mut network = NetworkPalyload::combine_from_file(config, ctx.work_dir());
+            network.update_with(ctx, online_peers).await?;
+            Ok::<_, ApiError>(network)
+        })
+        .buffered(FETCH_CONCURRENCY)
+        .try_collect()
         .await
-        .into_iter()
-        .collect::<Result<Vec<_>>>()?;
-
-    let networks = network_configs
-        .into_iter()
-        .map(|config| NetworkPalyload::combine_from_file(config, ctx.work_dir()))
-        .collect::<Vec<_>>();
-
-    let networks = join_all(networks.into_iter().map(|mut network| async {
-        network.update(&ctx).await?;
-        Ok(network)
-    }))
-    .await
-    .into_iter()
-    .collect::<Result<Vec<_>>>()?;
-
-    Ok(Json(networks))
 }
 
+/// Creates a network with a random id, `config` is sent to the controller.
+#[utoipa::path(
+    post,
+    path = "/network",
+    tag = "network",
+    request_body = NetworkPalyload,
+    responses((status = 200, body = NetworkPalyload)),
+)]
 async fn create_network(
     ctx: Ctx,
-    Json(mut network): Json<NetworkPalyload>,
+    Json(network): Json<NetworkPalyload>,
 ) -> Result<Json<NetworkPalyload>> {
+    Ok(Json(add_network(&ctx, network).await?))
+}
+
+pub(super) async fn add_network(
+    ctx: &Ctx,
+    mut network: NetworkPalyload,
+) -> Result<NetworkPalyload> {
     let config = network.config.take().unwrap_or_default();
     let config = ctx.create_network(&config).await?;
     network.config = Some(config);
-    network.update(&ctx).await?;
+    network.update(ctx).await?;
     network.write_to_file(ctx.work_dir())?;
-    Ok(Json(network))
+    Ok(network)
 }
 
-async fn get_network(ctx: Ctx, Path(network_id): Path<String>) -> Result<Json<NetworkPalyload>> {
-    let network_config = ctx.get_network(network_id.as_str()).await?;
+#[utoipa::path(
+    get,
+    path = "/network/{network_id}",
+    tag = "network",
+    params(("network_id" = String, Path, description = "16 hex digits network id")),
+    responses(
+        (status = 200, body = NetworkPalyload, headers(("ETag" = String))),
+        (status = 404),
+    ),
+)]
+async fn get_network(
+    ctx: Ctx,
+    Path(network_id): Path<String>,
+) -> Result<(HeaderMap, Json<NetworkPalyload>)> {
+    let network = fetch_network(&ctx, &network_id).await?;
+    Ok((etag::header(&network.etag()), Json(network)))
+}
+
+pub(super) async fn fetch_network(ctx: &Ctx, network_id: &str) -> Result<NetworkPalyload> {
+    let network_config = ctx.get_network(network_id).await?;
     let mut network = NetworkPalyload::combine_from_file(network_config, ctx.work_dir());
 
-    network.update(&ctx).await?;
-    Ok(Json(network))
+    network.update(ctx).await?;
+    Ok(network)
 }
 
+/// Updates the given fields, `config` is merged into the controller config.
+#[utoipa::path(
+    post,
+    path = "/network/{network_id}",
+    tag = "network",
+    params(
+        ("network_id" = String, Path, description = "16 hex digits network id"),
+        ("If-Match" = Option<String>, Header, description = "etag of the last read"),
+    ),
+    request_body = NetworkPalyload,
+    responses(
+        (status = 200, body = NetworkPalyload, headers(("ETag" = String))),
+        (status = 412),
+    ),
+)]
 async fn update_network(
     ctx: Ctx,
     Path(network_id): Path<String>,
-    Json(mut network): Json<NetworkPalyload>,
-) -> Result<Json<NetworkPalyload>> {
+    headers: HeaderMap,
+    Json(network): Json<NetworkPalyload>,
+) -> Result<(HeaderMap, Json<NetworkPalyload>)> {
+    // also without `If-Match`, not to land between the check and the write
+    // of another update.
+    let _guard = ctx.lock_updates().await;
+    if let Some(if_match) = etag::if_match(&headers) {
+        let current = fetch_network(&ctx.clone().without_cache(), &network_id).await?;
+        etag::check(&if_match, &current.etag())?;
+    }
+
+    let network = apply_network_update(&ctx, &network_id, network).await?;
+    Ok((etag::header(&network.etag()), Json(network)))
+}
+
+/// Patches the network as returned by `GET`, a JSON Merge Patch or a JSON
+/// Patch. Removed fields of `config` are reset to their empty or default value.
+#[utoipa::path(
+    patch,
+    path = "/network/{network_id}",
+    tag = "network",
+    params(
+        ("network_id" = String, Path, description = "16 hex digits network id"),
+        ("If-Match" = Option<String>, Header, description = "etag of the last read"),
+    ),
+    request_body(
+        content(
+            (Object = "application/merge-patch+json"),
+            (Vec<Object> = "application/json-patch+json"),
+        ),
+    ),
+    responses(
+        (status = 200, body = NetworkPalyload, headers(("ETag" = String))),
+        (status = 400),
+        (status = 404),
+        (status = 412),
+        (status = 415),
+    ),
+)]
+async fn patch_network(
+    ctx: Ctx,
+    Path(network_id): Path<String>,
+    headers: HeaderMap,
+    patch: Patch,
+) -> Result<(HeaderMap, Json<NetworkPalyload>)> {
+    let _guard = ctx.lock_updates().await;
+    let uncached = ctx.clone().without_cache();
+    let current = fetch_network(&uncached, &network_id).await?;
+    if let Some(if_match) = etag::if_match(&headers) {
+        etag::check(&if_match, &current.etag())?;
+    }
+
+    let mut document = serde_json::to_value(&current)?;
+    patch.apply(&mut document)?;
+    let mut network: NetworkPalyload = serde_json::from_value(document)
+        .map_err(|err| ApiError::BadRequest(format!("patched network: {}", err)))?;
+
+    if let Some(config) = changed_config(current.config.as_ref(), network.config.take())? {
+        uncached.update_network(&network_id, &config).await?;
+    }
+    network.id = Some(network_id.clone());
+    network.write_to_file(ctx.work_dir())?;
+
+    let network = fetch_network(&uncached, &network_id).await?;
+    Ok((etag::header(&network.etag()), Json(network)))
+}
+
+/// Applies a partial network update: `config` is sent to the controller, the
+/// other fields are merged into the `.ext.json` sidecar.
+pub(super) async fn apply_network_update(
+    ctx: &Ctx,
+    network_id: &str,
+    mut network: NetworkPalyload,
+) -> Result<NetworkPalyload> {
     let mut config = network.config.take();
     if let Some(network_config) = config.as_ref() {
-        config = Some(
-            ctx.update_network(network_id.as_str(), network_config)
-                .await?,
-        );
+        config = Some(ctx.update_network(network_id, network_config).await?);
     };
 
     network = assign_not_none_to(
         &network,
-        NetworkPalyload::read_from_file(ctx.work_dir(), &network_id).unwrap_or_else(|_| {
+        NetworkPalyload::read_from_file(ctx.work_dir(), network_id).unwrap_or_else(|_| {
             NetworkPalyload {
                 id: Some(network_id.to_string()),
                 ..Default::default()
@@ -98,19 +226,30 @@ async fn update_network(
     network.write_to_file(ctx.work_dir())?;
 
     if config.is_none() {
-        config = Some(ctx.get_network(&network_id).await?);
+        config = Some(ctx.get_network(network_id).await?);
     }
 
     network.config = config;
-    network.update(&ctx).await?;
+    network.update(ctx).await?;
 
-    Ok(Json(network))
+    Ok(network)
 }
 
+#[utoipa::path(
+    delete,
+    path = "/network/{network_id}",
+    tag = "network",
+    params(("network_id" = String, Path, description = "16 hex digits network id")),
+    responses((status = 200, body = NetworkPalyload), (status = 404)),
+)]
 async fn delete_network(ctx: Ctx, Path(network_id): Path<String>) -> Result<Json<NetworkPalyload>> {
-    let network_config = ctx.delete_network(network_id.as_str()).await?;
-    let file_path = network_file_path(ctx.work_dir(), &network_id);
-    Ok(Json(if file_path.exists() {
+    Ok(Json(remove_network(&ctx, &network_id).await?))
+}
+
+pub(super) async fn remove_network(ctx: &Ctx, network_id: &str) -> Result<NetworkPalyload> {
+    let network_config = ctx.delete_network(network_id).await?;
+    let file_path = network_file_path(ctx.work_dir(), network_id);
+    Ok(if file_path.exists() {
         let network = NetworkPalyload::combine_from_file(network_config, ctx.work_dir());
         let _ = std::fs::remove_file(file_path);
         network
@@ -119,30 +258,60 @@ async fn delete_network(ctx: Ctx, Path(network_id): Path<String>) -> Result<Json
             config: Some(network_config),
             ..Default::default()
         };
-        network.update(&ctx).await?;
+        network.update(ctx).await?;
         network
-    }))
+    })
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+/// Fields of the controller or computed on reads, kept out of the sidecar.
+const NOT_STORED: [&str; 5] = [
+    "config",
+    "clock",
+    "onlineMemberCount",
+    "authorizedMemberCount",
+    "totalMemberCount",
+];
+
+/// A network of the controller, `config` is the one of the controller, the
+/// other fields are stored by zerotier-edge.
+#[derive(Debug, Serialize, Deserialize, Default, ToSchema)]
 #[serde(rename_all = "camelCase")]
-struct NetworkPalyload {
-    id: Option<String>,
-    clock: Option<i64>,
-    config: Option<Map<String, Value>>,
-    description: Option<String>,
-    rules_source: Option<String>,
-    permissions: Option<Map<String, Value>>,
-    owner_id: Option<String>,
-    online_member_count: Option<usize>,
-    authorized_member_count: Option<usize>,
-    total_member_count: Option<usize>,
-    capabilities_by_name: Option<Map<String, Value>>,
-    tags_by_name: Option<Map<String, Value>>,
-    ui: Option<Map<String, Value>>,
+#[schema(as = Network)]
+pub(super) struct NetworkPalyload {
+    pub id: Option<String>,
+    pub clock: Option<i64>,
+    pub config: Option<Map<String, Value>>,
+    pub description: Option<String>,
+    pub rules_source: Option<String>,
+    pub permissions: Option<Map<String, Value>>,
+    pub owner_id: Option<String>,
+    pub online_member_count: Option<usize>,
+    pub authorized_member_count: Option<usize>,
+    pub total_member_count: Option<usize>,
+    pub capabilities_by_name: Option<Map<String, Value>>,
+    pub tags_by_name: Option<Map<String, Value>>,
+    pub ui: Option<Map<String, Value>>,
 }
 
 impl NetworkPalyload {
+    /// Changes with the controller config and the metadata set by clients.
+    pub fn etag(&self) -> String {
+        etag::compute(self.config.as_ref(), &self.metadata())
+    }
+
+    /// The fields set by clients, stored in the sidecar.
+    pub(super) fn metadata(&self) -> Value {
+        json!({
+            "description": self.description,
+            "rulesSource": self.rules_source,
+            "permissions": self.permissions,
+            "ownerId": self.owner_id,
+            "capabilitiesByName": self.capabilities_by_name,
+            "tagsByName": self.tags_by_name,
+            "ui": self.ui,
+        })
+    }
+
     fn combine_from_file(config: Map<String, Value>, work_dir: &std::path::Path) -> Self {
         let mut network = if let Some(network_id) = config.get("id").and_then(|e| e.as_str()) {
             let mut network = Self::read_from_file(work_dir, network_id).unwrap_or_default();
@@ -157,6 +326,12 @@ impl NetworkPalyload {
     }
 
     async fn update(&mut self, ctx: &Ctx) -> Result<()> {
+        let online_peers = peer::online_addresses(ctx).await?;
+        self.update_with(ctx, &online_peers).await
+    }
+
+    /// Like `update`, with the addresses of the online peers fetched already.
+    async fn update_with(&mut self, ctx: &Ctx, online_peers: &HashSet<String>) -> Result<()> {
         self.clock = Some(
             std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
@@ -176,55 +351,93 @@ impl NetworkPalyload {
             // fetch total_member_count and authorized_member_count
             let member_ids = ctx.get_member_ids(network_id).await?;
 
-            let members = join_all(
-                member_ids
-                    .iter()
-                    .map(|(member_id, _)| ctx.get_member(network_id, member_id)),
-            )
-            .await
-            .into_iter()
-            .collect::<Result<Vec<_>>>()?;
-
-            let total_member_count = members.len();
-            let authorized_member_count = members
-                .iter()
-                .flat_map(|m| m.get("authorized").and_then(|a| a.as_bool()))
-                .filter(|a| *a)
+            let total_member_count = member_ids.len();
+            let authorized_member_count = count_authorized(ctx, network_id, &member_ids).await?;
+
+            let online_member_count = member_ids
+                .keys()
+                .filter(|member_id| online_peers.contains(*member_id))
                 .count();
 
             self.total_member_count = Some(total_member_count);
             self.authorized_member_count = Some(authorized_member_count);
+            self.online_member_count = Some(online_member_count);
         }
 
         Ok(())
     }
 
-    fn read_from_file(work_dir: &std::path::Path, network_id: &str) -> Result<Self> {
+    pub(super) fn read_from_file(work_dir: &std::path::Path, network_id: &str) -> Result<Self> {
         let file_path = network_file_path(work_dir, network_id);
         let file = std::fs::File::open(file_path)?;
         let network = serde_json::from_reader(file)?;
         Ok(network)
     }
 
-    fn write_to_file(&mut self, work_dir: &std::path::Path) -> Result<()> {
+    pub(super) fn write_to_file(&self, work_dir: &std::path::Path) -> Result<()> {
         if let Some(network_id) = self.id.as_deref() {
-            let config = self.config.take();
             let file_path = network_file_path(work_dir, network_id);
+            write_sidecar(&file_path, self, &NOT_STORED)?;
+        }
+        Ok(())
+    }
+}
 
-            if let Some(dir) = file_path.parent() {
-                std::fs::create_dir_all(dir)?;
+/// Number of authorized members, only the members changed since they were
+/// last counted are fetched.
+async fn count_authorized(
+    ctx: &Ctx,
+    network_id: &str,
+    member_ids: &Map<String, Value>,
+) -> Result<usize> {
+    let mut known = 0;
+    let mut unknown = vec![];
+    for (member_id, revision) in member_ids {
+        match revision
+            .as_u64()
+            .and_then(|r| ctx.cache().authorized(network_id, member_id, r))
+        {
+            Some(authorized) => known += authorized as usize,
+            None => unknown.push(member_id.clone()),
+        }
+    }
+
+    let fetched = stream::iter(unknown)
+        .map(|member_id| async move {
+            let member = ctx.get_member(network_id, &member_id).await?;
+            let authorized = member
+                .get("authorized")
+                .and_then(|a| a.as_bool())
+                .unwrap_or_default();
+            if let Some(revision) = member.get("revision").and_then(|r| r.as_u64()) {
+                ctx.cache()
+                    .put_authorized(network_id, &member_id, revision, authorized);
+            }
+            Ok::<_, ApiError>(authorized)
+        })
+        .buffer_unordered(FETCH_CONCURRENCY)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    Ok(known + fetched.into_iter().filter(|a| *a).count())
+}
+
+/// Removes the sidecar of a network and the ones of its members.
+pub(super) fn remove_sidecars(work_dir: &std::path::Path, network_id: &str) -> Result<()> {
+    let file_path = network_file_path(work_dir, network_id);
+    let member_dir = file_path.with_file_name(network_id);
+    for result in [
+        std::fs::remove_file(&file_path).map_err(|err| (file_path.clone(), err)),
+        std::fs::remove_dir_all(&member_dir).map_err(|err| (member_dir.clone(), err)),
+    ] {
+        match result {
+            Err((path, err)) if err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(ApiError::Sidecar(path, err))
             }
-            let file = std::fs::File::options()
-                .write(true)
-                .truncate(true)
-                .create(true)
-                .open(&file_path)?;
-
-            serde_json::to_writer(file, &self)?;
-            self.config = config;
+            _ => (),
         }
-        Ok(())
     }
+    Ok(())
 }
 
 fn network_file_path(work_dir: &std::path::Path, network_id: &str) -> PathBuf {
@@ -0,0 +1,204 @@
+//! OpenAPI document of `/api/v1` and the API explorer serving it.
+
+use std::sync::LazyLock;
+
+use axum::{extract::OriginalUri, routing::get, Json, Router};
+use utoipa::{
+    openapi::{
+        security::{ApiKey, ApiKeyValue, SecurityScheme},
+        server::Server,
+        Content, OpenApi as Document, Ref, ResponseBuilder,
+    },
+    Modify, OpenApi,
+};
+
+use super::{
+    bulk, fleet, member, network, peer, presence, replication, template, ErrorBody, SharedState,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "zerotier-edge"),
+    paths(
+        super::status,
+        network::get_networks,
+        network::create_network,
+        network::get_network,
+        network::update_network,
+        network::patch_network,
+        network::delete_network,
+        member::get_members,
+        member::get_member,
+        member::update_member,
+        member::patch_member,
+        member::delete_member,
+        bulk::bulk_members,
+        presence::get_network_presence,
+        presence::get_member_presence,
+        peer::get_peers,
+        peer::get_peer,
+        peer::get_peer_history,
+        template::get_templates,
+        template::get_template,
+        template::put_template,
+        template::delete_template,
+        template::create_from_template,
+        fleet::get_controllers,
+        fleet::get_overview,
+        replication::get_replication,
+        replication::post_replication,
+    ),
+    components(schemas(ErrorBody)),
+    modifiers(&Security, &Errors),
+    security(("token" = [])),
+)]
+struct ApiDoc;
+
+static DOCUMENT: LazyLock<Document> = LazyLock::new(ApiDoc::openapi);
+
+/// The controller token, sent as `X-ZT1-AUTH` like to the controller itself.
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut Document) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "token",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-ZT1-AUTH"))),
+        );
+    }
+}
+
+/// Every operation may be rejected for the token or fail at the controller, and
+/// errors have the same body.
+struct Errors;
+
+impl Modify for Errors {
+    fn modify(&self, openapi: &mut Document) {
+        let error = |description: &str| {
+            ResponseBuilder::new()
+                .description(description)
+                .content(
+                    "application/json",
+                    Content::new(Some(Ref::from_schema_name("ErrorBody"))),
+                )
+                .build()
+        };
+
+        for item in openapi.paths.paths.values_mut() {
+            let operations = [
+                &mut item.get,
+                &mut item.post,
+                &mut item.put,
+                &mut item.patch,
+                &mut item.delete,
+            ];
+            for operation in operations.into_iter().flatten() {
+                let responses = &mut operation.responses.responses;
+                for (status, response) in responses.iter_mut() {
+                    if status.starts_with(['4', '5']) {
+                        *response = error(status_description(status)).into();
+                    }
+                }
+                for status in ["401", "502"] {
+                    responses
+                        .entry(status.to_string())
+                        .or_insert_with(|| error(status_description(status)).into());
+                }
+            }
+        }
+    }
+}
+
+fn status_description(status: &str) -> &'static str {
+    match status {
+        "400" => "invalid request",
+        "401" => "missing or rejected token",
+        "404" => "not found",
+        "412" => "modified since the etag of `If-Match`, or a failed `test` operation",
+        "415" => "not a JSON Merge Patch or JSON Patch",
+        "502" => "the controller is unreachable or failed",
+        _ => "error",
+    }
+}
+
+#[inline]
+pub fn routes() -> Router<SharedState> {
+    let router = Router::new().route("/openapi.json", get(get_document));
+
+    #[cfg(feature = "explorer")]
+    let router = router
+        .route("/docs", get(explorer::redirect))
+        .route("/docs/", get(explorer::get_explorer))
+        .route("/docs/*file", get(explorer::get_explorer));
+
+    router
+}
+
+/// The document, with the server under the path it was requested from so that
+/// it works behind a base path.
+async fn get_document(OriginalUri(uri): OriginalUri) -> Json<Document> {
+    let mut document = DOCUMENT.clone();
+    let server = uri.path().trim_end_matches("/openapi.json");
+    document.servers = Some(vec![Server::new(server)]);
+    Json(document)
+}
+
+#[cfg(feature = "explorer")]
+mod explorer {
+    use std::sync::{Arc, LazyLock};
+
+    use axum::{
+        extract::Path,
+        http::{header, StatusCode},
+        response::{IntoResponse, Redirect, Response},
+    };
+    use utoipa_swagger_ui::Config;
+
+    /// Loads the document next to the directory of the explorer.
+    static CONFIG: LazyLock<Arc<Config<'static>>> =
+        LazyLock::new(|| Arc::new(Config::new(["../openapi.json"])));
+
+    /// Relative, to keep the base path.
+    pub async fn redirect() -> Redirect {
+        Redirect::permanent("docs/")
+    }
+
+    pub async fn get_explorer(file: Option<Path<String>>) -> Response {
+        let file = file.as_ref().map(|f| f.as_str()).unwrap_or_default();
+        match utoipa_swagger_ui::serve(file, CONFIG.clone()) {
+            Ok(Some(file)) => (
+                [(header::CONTENT_TYPE, file.content_type)],
+                file.bytes.into_owned(),
+            )
+                .into_response(),
+            Ok(None) => StatusCode::NOT_FOUND.into_response(),
+            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document() {
+        let document = serde_json::to_value(&*DOCUMENT).unwrap();
+        let paths = document["paths"].as_object().unwrap();
+        assert!(paths.contains_key("/network/{network_id}/member/{member_id}"));
+        for (path, item) in paths {
+            for (method, operation) in item.as_object().unwrap() {
+                assert!(
+                    operation["responses"]["401"].is_object(),
+                    "{} {} has no 401 response",
+                    method,
+                    path
+                );
+            }
+        }
+        let schemas = &document["components"]["schemas"];
+        assert!(schemas["Network"].is_object());
+        assert!(schemas["Member"].is_object());
+    }
+}
@@ -0,0 +1,254 @@
+//! PATCH bodies, RFC 7396 JSON Merge Patch and RFC 6902 JSON Patch, applied
+//! to the JSON document of a network or member.
+
+use async_trait::async_trait;
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::header::CONTENT_TYPE,
+};
+use json_patch::PatchErrorKind;
+use serde_json::{json, Map, Value};
+
+use super::{ApiError, FieldError, Result};
+
+pub(super) const MERGE_PATCH: &str = "application/merge-patch+json";
+pub(super) const JSON_PATCH: &str = "application/json-patch+json";
+
+#[derive(Debug)]
+pub(super) enum Patch {
+    /// `null` removes a field, objects are merged recursively.
+    Merge(Value),
+    /// operations applied in order, all or nothing.
+    Json(json_patch::Patch),
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequest<S> for Patch {
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self> {
+        let content_type = request
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .map(|v| v.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+
+        let body = Bytes::from_request(request, state)
+            .await
+            .map_err(|err| ApiError::BadRequest(err.body_text()))?;
+        let invalid = |err: serde_json::Error| ApiError::BadRequest(err.to_string());
+
+        match content_type.as_str() {
+            MERGE_PATCH => Ok(Patch::Merge(
+                serde_json::from_slice(&body).map_err(invalid)?,
+            )),
+            JSON_PATCH => Ok(Patch::Json(serde_json::from_slice(&body).map_err(invalid)?)),
+            _ => Err(ApiError::UnsupportedMediaType(format!(
+                "use {} or {}",
+                MERGE_PATCH, JSON_PATCH
+            ))),
+        }
+    }
+}
+
+impl Patch {
+    pub fn apply(&self, document: &mut Value) -> Result<()> {
+        match self {
+            Patch::Merge(patch) => {
+                json_patch::merge(document, patch);
+                Ok(())
+            }
+            Patch::Json(patch) => {
+                json_patch::patch(document, patch).map_err(|err| match err.kind {
+                    PatchErrorKind::TestFailed => ApiError::PreconditionFailed(err.to_string()),
+                    _ => ApiError::BadRequest(err.to_string()),
+                })
+            }
+        }
+    }
+}
+
+/// Fields of the config set by the controller, removing them changes nothing.
+const READ_ONLY: [&str; 14] = [
+    "id",
+    "nwid",
+    "address",
+    "objtype",
+    "revision",
+    "creationTime",
+    "lastAuthorizedTime",
+    "lastDeauthorizedTime",
+    "lastAuthorizedCredential",
+    "lastAuthorizedCredentialType",
+    "vMajor",
+    "vMinor",
+    "vRev",
+    "vProto",
+];
+
+/// The patched controller config to send, `None` if unchanged. The controller
+/// keeps every field, so removed fields are sent with their empty or default
+/// value instead.
+pub(super) fn changed_config(
+    current: Option<&Map<String, Value>>,
+    patched: Option<Map<String, Value>>,
+) -> Result<Option<Map<String, Value>>> {
+    let current = current.cloned().unwrap_or_default();
+    let mut patched = patched
+        .ok_or_else(|| ApiError::invalid("config", "cannot be removed from the controller"))?;
+
+    let mut invalid = vec![];
+    for (key, value) in &current {
+        if patched.contains_key(key) || READ_ONLY.contains(&key.as_str()) {
+            continue;
+        }
+        match cleared(key, value) {
+            Some(cleared) => {
+                patched.insert(key.clone(), cleared);
+            }
+            None => invalid.push(FieldError {
+                field: format!("config.{}", key),
+                message: "has no default to reset to, set a value instead".to_string(),
+            }),
+        }
+    }
+    if !invalid.is_empty() {
+        return Err(ApiError::InvalidFields(invalid));
+    }
+    for key in READ_ONLY {
+        if let Some(value) = current.get(key) {
+            patched.insert(key.to_string(), value.clone());
+        }
+    }
+
+    Ok((patched != current).then_some(patched))
+}
+
+/// The value the controller takes as unset for a removed config field.
+pub(super) fn cleared(key: &str, current: &Value) -> Option<Value> {
+    Some(match key {
+        "mtu" => json!(2800),
+        "multicastLimit" => json!(32),
+        "dns" => json!({"domain": "", "servers": []}),
+        _ => match current {
+            Value::Array(_) => json!([]),
+            Value::Object(_) => json!({}),
+            Value::String(_) => json!(""),
+            Value::Bool(_) => json!(false),
+            Value::Null => Value::Null,
+            Value::Number(_) => return None,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_patch() {
+        let document = json!({
+            "name": "a",
+            "description": "b",
+            "config": {"authorized": false, "ipAssignments": ["10.0.0.1"], "tags": [[1, 2]]},
+        });
+
+        let mut merged = document.clone();
+        Patch::Merge(json!({"description": null, "config": {"authorized": true}}))
+            .apply(&mut merged)
+            .unwrap();
+        assert_eq!(
+            merged,
+            json!({
+                "name": "a",
+                "config": {"authorized": true, "ipAssignments": ["10.0.0.1"], "tags": [[1, 2]]},
+            })
+        );
+
+        let mut patched = document.clone();
+        let patch = serde_json::from_value(json!([
+            {"op": "test", "path": "/name", "value": "a"},
+            {"op": "add", "path": "/config/ipAssignments/-", "value": "10.0.0.2"},
+            {"op": "remove", "path": "/description"},
+        ]))
+        .unwrap();
+        Patch::Json(patch).apply(&mut patched).unwrap();
+        assert_eq!(
+            patched["config"]["ipAssignments"],
+            json!(["10.0.0.1", "10.0.0.2"])
+        );
+        assert!(patched.get("description").is_none());
+
+        let patch = serde_json::from_value(json!([
+            {"op": "test", "path": "/name", "value": "x"},
+        ]))
+        .unwrap();
+        assert!(matches!(
+            Patch::Json(patch).apply(&mut patched.clone()),
+            Err(ApiError::PreconditionFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_changed_config() {
+        let current = json!({"authorized": false, "name": "a"});
+        let current = current.as_object();
+
+        let same = current.cloned();
+        assert!(changed_config(current, same).unwrap().is_none());
+
+        let changed = json!({"authorized": true, "name": "a"});
+        assert!(changed_config(current, changed.as_object().cloned())
+            .unwrap()
+            .is_some());
+
+        let removed = json!({"authorized": true});
+        let removed = changed_config(current, removed.as_object().cloned()).unwrap();
+        assert_eq!(removed.unwrap()["name"], "");
+        assert!(changed_config(current, None).is_err());
+
+        let current = json!({"id": "a", "priority": 1});
+        let removed = json!({});
+        assert!(changed_config(current.as_object(), removed.as_object().cloned()).is_err());
+    }
+
+    #[test]
+    fn test_patch_config_to_null() {
+        let current = json!({
+            "description": "b",
+            "config": {
+                "id": "8056c2e21c000001",
+                "name": "office",
+                "mtu": 1280,
+                "routes": [{"target": "10.1.0.0/16", "via": null}],
+                "dns": {"domain": "office.lan", "servers": ["10.1.0.1"]},
+            },
+        });
+        let mut patched = current.clone();
+        Patch::Merge(json!({"config": {"routes": null, "dns": null, "mtu": null}}))
+            .apply(&mut patched)
+            .unwrap();
+
+        let config = changed_config(
+            current["config"].as_object(),
+            patched["config"].as_object().cloned(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            Value::Object(config),
+            json!({
+                "id": "8056c2e21c000001",
+                "name": "office",
+                "mtu": 2800,
+                "routes": [],
+                "dns": {"domain": "", "servers": []},
+            })
+        );
+    }
+}
@@ -1,22 +1,280 @@
-use super::{ctx::Ctx, Result, SharedState};
+use std::{collections::HashSet, path::PathBuf, time::Duration};
+
+use super::{
+    ctx::Ctx,
+    extract::Query,
+    history::{self, history_dir, now_millis, Window},
+    ApiError, Result, SharedState,
+};
 use axum::{extract::Path, routing::get, Json, Router};
+use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
+use utoipa::ToSchema;
 
 #[inline]
 pub fn routes() -> Router<SharedState> {
     Router::new()
         .route("/peer", get(get_peers))
         .route("/peer/:address", get(get_peer))
+        .route("/peer/:address/history", get(get_peer_history))
 }
 
+/// Peers of the node, as reported by the controller.
+#[utoipa::path(
+    get,
+    path = "/peer",
+    tag = "peer",
+    responses((status = 200, body = [Object])),
+)]
 async fn get_peers(ctx: Ctx) -> Result<Json<Vec<Peer>>> {
     let peers = ctx.get_peers().await?;
     Ok(Json(peers))
 }
 
+#[utoipa::path(
+    get,
+    path = "/peer/{address}",
+    tag = "peer",
+    params(("address" = String, Path, description = "10 hex digits node address")),
+    responses((status = 200, body = Object), (status = 404)),
+)]
 async fn get_peer(ctx: Ctx, Path(address): Path<String>) -> Result<Json<Peer>> {
     let peer = ctx.get_peer(&address).await?;
     Ok(Json(peer))
 }
 
+/// Latency and path history of a peer.
+#[utoipa::path(
+    get,
+    path = "/peer/{address}/history",
+    tag = "peer",
+    params(("address" = String, Path, description = "10 hex digits node address"), Window),
+    responses((status = 200, body = PeerHistory), (status = 400)),
+)]
+async fn get_peer_history(
+    ctx: Ctx,
+    Path(address): Path<String>,
+    Query(window): Query<Window>,
+) -> Result<Json<PeerHistory>> {
+    // the address becomes a file name.
+    if !is_address(&address) {
+        return Err(ApiError::invalid("address", "must be 10 hex digits"));
+    }
+
+    // reading local files does not check the token, so ask the controller.
+    ctx.get_status().await?;
+
+    let (since, until) = window.range();
+    let samples = history::read::<PeerSample>(&peer_file_path(ctx.work_dir(), &address))?
+        .into_iter()
+        .filter(|s| s.time >= since && s.time <= until)
+        .collect::<Vec<_>>();
+
+    Ok(Json(PeerHistory {
+        summary: PeerSummary::from_samples(&samples),
+        address,
+        samples,
+    }))
+}
+
 type Peer = Map<String, Value>;
+
+/// State of the connection to a peer at a point of time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct PeerSample {
+    pub time: i64,
+    /// round trip time in milliseconds, if known.
+    pub latency: Option<i64>,
+    /// number of physical paths to the peer.
+    pub paths: usize,
+    /// no direct path, the traffic is relayed by a root.
+    pub relayed: bool,
+    /// physical address of the preferred path.
+    pub physical_address: Option<String>,
+}
+
+impl PeerSample {
+    pub fn from_peer(peer: &Peer, time: i64) -> Self {
+        let paths = peer
+            .get("paths")
+            .and_then(|x| x.as_array())
+            .map(|paths| paths.as_slice())
+            .unwrap_or_default();
+
+        let direct = paths.iter().any(|p| {
+            p.get("active").and_then(|x| x.as_bool()) != Some(false)
+                && p.get("expired").and_then(|x| x.as_bool()) != Some(true)
+        });
+
+        let physical_address = paths
+            .iter()
+            .find(|p| matches!(p.get("preferred"), Some(Value::Bool(true))))
+            .and_then(|p| p.get("address"))
+            .and_then(|x| x.as_str())
+            .map(|s| s.split('/').next().unwrap_or(s).to_string());
+
+        Self {
+            time,
+            latency: peer
+                .get("latency")
+                .and_then(|x| x.as_i64())
+                .filter(|l| *l >= 0),
+            paths: paths.len(),
+            relayed: !direct,
+            physical_address,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct PeerHistory {
+    address: String,
+    summary: PeerSummary,
+    samples: Vec<PeerSample>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct PeerSummary {
+    samples: usize,
+    median_latency: Option<i64>,
+    min_latency: Option<i64>,
+    max_latency: Option<i64>,
+    /// share of the samples without a direct path.
+    relayed_percent: Option<f64>,
+    /// how often the preferred physical address changed.
+    address_changes: usize,
+}
+
+impl PeerSummary {
+    fn from_samples(samples: &[PeerSample]) -> Self {
+        let mut latencies = samples.iter().filter_map(|s| s.latency).collect::<Vec<_>>();
+        latencies.sort_unstable();
+
+        let median_latency = match latencies.len() {
+            0 => None,
+            n if n % 2 == 1 => Some(latencies[n / 2]),
+            n => Some((latencies[n / 2 - 1] + latencies[n / 2]) / 2),
+        };
+
+        let relayed = samples.iter().filter(|s| s.relayed).count();
+
+        let address_changes = samples
+            .windows(2)
+            .filter(|w| {
+                w[1].physical_address.is_some() && w[0].physical_address != w[1].physical_address
+            })
+            .count();
+
+        Self {
+            samples: samples.len(),
+            median_latency,
+            min_latency: latencies.first().copied(),
+            max_latency: latencies.last().copied(),
+            relayed_percent: (!samples.is_empty())
+                .then(|| relayed as f64 * 100.0 / samples.len() as f64),
+            address_changes,
+        }
+    }
+}
+
+/// Appends the current state of every peer to its history.
+pub(super) async fn sample(ctx: &Ctx) -> Result<()> {
+    let now = now_millis();
+    for peer in ctx.get_peers().await? {
+        if let Some(address) = peer.get("address").and_then(|x| x.as_str()) {
+            history::append(
+                &peer_file_path(ctx.work_dir(), address),
+                &PeerSample::from_peer(&peer, now),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub(super) fn compact(work_dir: &std::path::Path, cutoff: i64) -> std::io::Result<()> {
+    history::compact::<PeerSample>(
+        &history_dir(work_dir).join("peer"),
+        cutoff,
+        |s| s.time,
+        false,
+    )
+}
+
+/// A node address, 10 hex digits.
+fn is_address(s: &str) -> bool {
+    s.len() == 10 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn peer_file_path(work_dir: &std::path::Path, address: &str) -> PathBuf {
+    history_dir(work_dir)
+        .join("peer")
+        .join(format!("{}.jsonl", address))
+}
+
+/// Addresses of the peers that are online.
+pub(super) async fn online_addresses(ctx: &Ctx) -> Result<HashSet<String>> {
+    Ok(ctx
+        .get_peers()
+        .await?
+        .into_iter()
+        .filter(|peer| is_online(peer, ctx.online_threshold()))
+        .filter_map(|peer| peer.get("address")?.as_str().map(|s| s.to_string()))
+        .collect())
+}
+
+/// Whether any path of the peer received a packet within `threshold`.
+pub(super) fn is_online(peer: &Peer, threshold: Duration) -> bool {
+    let now = now_millis();
+    peer.get("paths")
+        .and_then(|x| x.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|path| path.get("lastReceive").and_then(|x| x.as_i64()))
+        .any(|last_receive| now - last_receive < threshold.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{is_address, PeerSample, PeerSummary};
+
+    #[test]
+    fn test_peer_summary() {
+        let peer = |latency: i64, paths: serde_json::Value| {
+            json!({ "latency": latency, "paths": paths })
+                .as_object()
+                .cloned()
+                .unwrap()
+        };
+        let path =
+            |address: &str| json!([{ "address": address, "active": true, "preferred": true }]);
+
+        let samples = [
+            PeerSample::from_peer(&peer(10, path("1.1.1.1/9993")), 0),
+            PeerSample::from_peer(&peer(30, json!([])), 1),
+            PeerSample::from_peer(&peer(20, path("2.2.2.2/9993")), 2),
+            PeerSample::from_peer(&peer(-1, path("2.2.2.2/9993")), 3),
+        ];
+
+        assert_eq!(samples[0].physical_address.as_deref(), Some("1.1.1.1"));
+        assert!(samples[1].relayed);
+        assert_eq!(samples[3].latency, None);
+
+        let summary = PeerSummary::from_samples(&samples);
+        assert_eq!(summary.median_latency, Some(20));
+        assert_eq!(summary.relayed_percent, Some(25.0));
+        assert_eq!(summary.address_changes, 1);
+    }
+
+    #[test]
+    fn test_is_address() {
+        assert!(is_address("8056c2e21c"));
+        assert!(!is_address("..%2F..%2Fx"));
+        assert!(!is_address("../../etc/x"));
+        assert!(!is_address("8056c2e21c0"));
+    }
+}
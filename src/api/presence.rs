@@ -0,0 +1,398 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    time::Duration,
+};
+
+use axum::{extract::Path, routing::get, Json, Router};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::{
+    ctx::Ctx,
+    extract::Query,
+    history::{self, history_dir, now_millis, Window},
+    peer, Result, SharedState,
+};
+
+#[inline]
+pub fn routes() -> Router<SharedState> {
+    Router::new()
+        .route("/network/:network_id/presence", get(get_network_presence))
+        .route(
+            "/network/:network_id/member/:member_id/presence",
+            get(get_member_presence),
+        )
+}
+
+/// Online history summary of every member of a network.
+#[utoipa::path(
+    get,
+    path = "/network/{network_id}/presence",
+    tag = "member",
+    params(("network_id" = String, Path, description = "16 hex digits network id"), Window),
+    responses((status = 200, body = [Presence])),
+)]
+async fn get_network_presence(
+    ctx: Ctx,
+    Path(network_id): Path<String>,
+    Query(window): Query<Window>,
+) -> Result<Json<Vec<Presence>>> {
+    let (since, until) = window.range();
+    let member_ids = ctx.get_member_ids(&network_id).await?;
+    let sampled = sampled_spans(&history::read(&samples_file_path(ctx.work_dir()))?);
+
+    let mut presences = Vec::with_capacity(member_ids.len());
+    for member_id in member_ids.keys() {
+        let transitions =
+            history::read(&presence_file_path(ctx.work_dir(), &network_id, member_id))?;
+        presences.push(Presence::summarize(
+            member_id,
+            &transitions,
+            &sampled,
+            since,
+            until,
+        ));
+    }
+
+    Ok(Json(presences))
+}
+
+/// Online history of a member, with the timeline of its online states.
+#[utoipa::path(
+    get,
+    path = "/network/{network_id}/member/{member_id}/presence",
+    tag = "member",
+    params(
+        ("network_id" = String, Path, description = "16 hex digits network id"),
+        ("member_id" = String, Path, description = "10 hex digits node id"),
+        Window,
+    ),
+    responses((status = 200, body = Presence), (status = 404)),
+)]
+async fn get_member_presence(
+    ctx: Ctx,
+    Path((network_id, member_id)): Path<(String, String)>,
+    Query(window): Query<Window>,
+) -> Result<Json<Presence>> {
+    // make sure the member exists and the token is valid.
+    ctx.get_member(&network_id, &member_id).await?;
+
+    let (since, until) = window.range();
+    let transitions = history::read(&presence_file_path(ctx.work_dir(), &network_id, &member_id))?;
+    let sampled = sampled_spans(&history::read(&samples_file_path(ctx.work_dir()))?);
+    let mut presence = Presence::summarize(&member_id, &transitions, &sampled, since, until);
+    presence.timeline = Some(timeline(&transitions, &sampled, since, until));
+
+    Ok(Json(presence))
+}
+
+/// A change of the online state of a member, as stored in the history file.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Transition {
+    pub time: i64,
+    pub online: bool,
+}
+
+/// A run of the sampler, covering the `interval` after it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Sample {
+    pub time: i64,
+    pub interval: i64,
+}
+
+/// A period in which the sampler ran.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Span {
+    start: i64,
+    end: i64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct Presence {
+    member_id: String,
+    /// state at the end of the window, unknown when it was not sampled.
+    online: Option<bool>,
+    /// share of the sampled time in the window the member was online.
+    uptime_percent: Option<f64>,
+    last_online: Option<i64>,
+    last_offline: Option<i64>,
+    /// number of state changes in the window.
+    transitions: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeline: Option<Vec<Segment>>,
+}
+
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+struct Segment {
+    start: i64,
+    end: i64,
+    online: bool,
+}
+
+impl Presence {
+    fn summarize(
+        member_id: &str,
+        transitions: &[Transition],
+        sampled: &[Span],
+        since: i64,
+        until: i64,
+    ) -> Self {
+        let segments = timeline(transitions, sampled, since, until);
+        let total = segments.iter().map(|s| s.end - s.start).sum::<i64>();
+        let online_time = segments
+            .iter()
+            .filter(|s| s.online)
+            .map(|s| s.end - s.start)
+            .sum::<i64>();
+
+        // the last moment in each state, looking before the window as well.
+        let all = timeline(transitions, sampled, i64::MIN, until);
+        let last_in = |online: bool| all.iter().rev().find(|s| s.online == online).map(|s| s.end);
+
+        Self {
+            member_id: member_id.to_string(),
+            online: all.last().filter(|s| s.end == until).map(|s| s.online),
+            uptime_percent: (total > 0).then(|| online_time as f64 * 100.0 / total as f64),
+            last_online: last_in(true),
+            last_offline: last_in(false),
+            transitions: transitions
+                .iter()
+                .filter(|t| t.time >= since && t.time <= until)
+                .count(),
+            timeline: None,
+        }
+    }
+}
+
+/// Splits the sampled time of `[since, until]` into periods of constant
+/// state. The time before the first transition and the gaps without samples,
+/// e.g. while zerotier-edge was stopped, are unknown and left out.
+fn timeline(transitions: &[Transition], sampled: &[Span], since: i64, until: i64) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = vec![];
+    for (i, transition) in transitions.iter().enumerate() {
+        let end = transitions.get(i + 1).map_or(until, |t| t.time);
+        let (start, end) = (transition.time.max(since), end.min(until));
+        for span in sampled {
+            let (start, end) = (start.max(span.start), end.min(span.end));
+            if start >= end {
+                continue;
+            }
+            match segments.last_mut() {
+                Some(last) if last.online == transition.online && last.end == start => {
+                    last.end = end
+                }
+                _ => segments.push(Segment {
+                    start,
+                    end,
+                    online: transition.online,
+                }),
+            }
+        }
+    }
+    segments
+}
+
+/// The periods covered by `samples`, with half an interval of slack for
+/// samples running late.
+fn sampled_spans(samples: &[Sample]) -> Vec<Span> {
+    let mut spans: Vec<Span> = vec![];
+    for sample in samples {
+        let (start, end) = (sample.time, sample.time + sample.interval * 3 / 2);
+        match spans.last_mut() {
+            Some(last) if start <= last.end => last.end = last.end.max(end),
+            _ => spans.push(Span { start, end }),
+        }
+    }
+    spans
+}
+
+/// Records the transitions of the online state of members, and when they
+/// were sampled.
+#[derive(Debug)]
+pub struct Sampler {
+    interval: Duration,
+    last: HashMap<PathBuf, bool>,
+}
+
+impl Sampler {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: Default::default(),
+        }
+    }
+
+    pub async fn sample(&mut self, ctx: &Ctx) -> Result<()> {
+        let online_members = peer::online_addresses(ctx).await?;
+        let now = now_millis();
+
+        let mut sampled = HashSet::new();
+        for network_id in ctx.get_network_ids().await? {
+            for member_id in ctx.get_member_ids(&network_id).await?.keys() {
+                let online = online_members.contains(member_id);
+                let file_path = presence_file_path(ctx.work_dir(), &network_id, member_id);
+                sampled.insert(file_path.clone());
+
+                let last = match self.last.get(&file_path) {
+                    Some(last) => Some(*last),
+                    None => history::read::<Transition>(&file_path)?
+                        .last()
+                        .map(|t| t.online),
+                };
+
+                if last != Some(online) {
+                    history::append(&file_path, &Transition { time: now, online })?;
+                }
+                self.last.insert(file_path, online);
+            }
+        }
+        // forget deleted members.
+        self.last.retain(|file_path, _| sampled.contains(file_path));
+
+        history::append(
+            &samples_file_path(ctx.work_dir()),
+            &Sample {
+                time: now,
+                interval: self.interval.as_millis() as i64,
+            },
+        )?;
+        Ok(())
+    }
+}
+
+pub fn compact(work_dir: &std::path::Path, cutoff: i64) -> std::io::Result<()> {
+    history::compact::<Transition>(
+        &history_dir(work_dir).join("presence"),
+        cutoff,
+        |t| t.time,
+        true,
+    )?;
+    history::compact::<Sample>(
+        &history_dir(work_dir).join("samples"),
+        cutoff,
+        |s| s.time,
+        false,
+    )
+}
+
+fn samples_file_path(work_dir: &std::path::Path) -> PathBuf {
+    history_dir(work_dir).join("samples").join("presence.jsonl")
+}
+
+fn presence_file_path(work_dir: &std::path::Path, network_id: &str, member_id: &str) -> PathBuf {
+    history_dir(work_dir)
+        .join("presence")
+        .join(network_id)
+        .join(format!("{}.jsonl", member_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sampled_spans, timeline, Presence, Sample, Segment, Span, Transition};
+
+    fn samples(times: impl IntoIterator<Item = i64>) -> Vec<Span> {
+        let samples = times
+            .into_iter()
+            .map(|time| Sample { time, interval: 10 })
+            .collect::<Vec<_>>();
+        sampled_spans(&samples)
+    }
+
+    #[test]
+    fn test_presence_summary() {
+        let transitions = [
+            Transition {
+                time: 0,
+                online: true,
+            },
+            Transition {
+                time: 60,
+                online: false,
+            },
+            Transition {
+                time: 90,
+                online: true,
+            },
+        ];
+
+        let sampled = samples((0..=100).step_by(10));
+        assert_eq!(sampled, [Span { start: 0, end: 115 }]);
+
+        let segments = timeline(&transitions, &sampled, 30, 100);
+        assert_eq!(
+            segments,
+            [
+                Segment {
+                    start: 30,
+                    end: 60,
+                    online: true
+                },
+                Segment {
+                    start: 60,
+                    end: 90,
+                    online: false
+                },
+                Segment {
+                    start: 90,
+                    end: 100,
+                    online: true
+                },
+            ]
+        );
+
+        let presence = Presence::summarize("a", &transitions, &sampled, 30, 100);
+        assert_eq!(presence.online, Some(true));
+        assert_eq!(presence.uptime_percent, Some(40.0 * 100.0 / 70.0));
+        assert_eq!(presence.last_online, Some(100));
+        assert_eq!(presence.last_offline, Some(90));
+        assert_eq!(presence.transitions, 2);
+
+        let presence = Presence::summarize("a", &transitions, &sampled, 0, 80);
+        assert_eq!(presence.online, Some(false));
+        assert_eq!(presence.last_online, Some(60));
+    }
+
+    #[test]
+    fn test_presence_unsampled_gap() {
+        // stopped after the sample at 20, started again at 100.
+        let sampled = samples([0, 10, 20, 100, 110]);
+        let transitions = [
+            Transition {
+                time: 0,
+                online: true,
+            },
+            Transition {
+                time: 100,
+                online: false,
+            },
+        ];
+
+        let segments = timeline(&transitions, &sampled, 0, 120);
+        assert_eq!(
+            segments,
+            [
+                Segment {
+                    start: 0,
+                    end: 35,
+                    online: true
+                },
+                Segment {
+                    start: 100,
+                    end: 120,
+                    online: false
+                },
+            ]
+        );
+
+        let presence = Presence::summarize("a", &transitions, &sampled, 0, 120);
+        assert_eq!(presence.uptime_percent, Some(35.0 * 100.0 / 55.0));
+        assert_eq!(presence.online, Some(false));
+
+        // not sampled at the end of the window
+        let presence = Presence::summarize("a", &transitions, &sampled, 0, 60);
+        assert_eq!(presence.online, None);
+        assert_eq!(presence.uptime_percent, Some(100.0));
+    }
+}
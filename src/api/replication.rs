@@ -0,0 +1,586 @@
+//! Replication of a primary controller to a standby one: networks, members and
+//! their sidecar metadata are copied through the controller apis, so that a
+//! standby with the identity of the primary restored can take over.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::{extract::State, http::HeaderMap, routing::get, Router};
+use futures::{stream, StreamExt, TryStreamExt};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+
+use super::{
+    cache::FETCH_CONCURRENCY,
+    ctx::Ctx,
+    extract::Json,
+    history::now_millis,
+    member::{member_file_path, MemberPayload},
+    network::{remove_sidecars, NetworkPalyload},
+    patch::cleared,
+    ApiError, ControllerState, Result, SharedState,
+};
+use crate::log;
+
+/// Fields of a network config set by the controller itself.
+const NETWORK_VOLATILE: [&str; 5] = ["id", "nwid", "objtype", "revision", "creationTime"];
+
+/// Fields of a member config set by the controller or reported by the member.
+const MEMBER_VOLATILE: [&str; 15] = [
+    "id",
+    "address",
+    "nwid",
+    "objtype",
+    "revision",
+    "creationTime",
+    "lastAuthorizedTime",
+    "lastDeauthorizedTime",
+    "lastAuthorizedCredential",
+    "lastAuthorizedCredentialType",
+    "authenticationExpiryTime",
+    "vMajor",
+    "vMinor",
+    "vRev",
+    "vProto",
+];
+
+#[inline]
+pub fn routes() -> Router<SharedState> {
+    Router::new().route("/replication", get(get_replication).post(post_replication))
+}
+
+/// Copies `primary` to `standby` every `interval`.
+#[derive(Debug)]
+pub struct Replication {
+    pub primary: Arc<ControllerState>,
+    pub standby: Arc<ControllerState>,
+    pub interval: Duration,
+    /// only report the drift, the standby is left unchanged.
+    pub dry_run: bool,
+    /// delete the networks and members of the standby missing on the primary.
+    pub prune: bool,
+    report: RwLock<Option<Report>>,
+    /// held by a running replication.
+    running: Mutex<()>,
+}
+
+/// Result of the last replication.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    primary: String,
+    standby: String,
+    dry_run: bool,
+    /// start of the replication in milliseconds.
+    started_at: i64,
+    /// end of the replication in milliseconds.
+    finished_at: i64,
+    /// differences of the standby, changed to match the primary if `synced`.
+    drift: Vec<Drift>,
+    /// why the replication stopped early, the drift found until then is kept.
+    /// A failing network does not stop it, its drift has the `error`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct Drift {
+    network_id: String,
+    /// missing for the drift of the network itself.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    member_id: Option<String>,
+    kind: DriftKind,
+    /// the config fields that differ.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<String>,
+    synced: bool,
+    /// being synced, until it succeeds or fails.
+    #[serde(skip)]
+    syncing: bool,
+    /// why the drift was not synced, or the network not compared.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum DriftKind {
+    /// only on the primary.
+    Missing,
+    /// only on the standby.
+    Extra,
+    /// the controller configs differ.
+    Config,
+    /// the metadata of the `.ext.json` sidecars differ.
+    Metadata,
+    /// the network could not be compared, see `error`.
+    Unknown,
+}
+
+impl Replication {
+    pub fn new(
+        primary: Arc<ControllerState>,
+        standby: Arc<ControllerState>,
+        interval: Duration,
+        dry_run: bool,
+        prune: bool,
+    ) -> Self {
+        Self {
+            primary,
+            standby,
+            interval,
+            dry_run,
+            prune,
+            report: Default::default(),
+            running: Mutex::new(()),
+        }
+    }
+
+    pub fn report(&self) -> Option<Report> {
+        self.report.read().ok().and_then(|r| r.clone())
+    }
+
+    /// Replicates once, after the running replication if any.
+    async fn run(&self, state: &SharedState) -> Report {
+        let _guard = self.running.lock().await;
+        let mut report = Report {
+            primary: self.primary.name.clone(),
+            standby: self.standby.name.clone(),
+            dry_run: self.dry_run,
+            started_at: now_millis(),
+            finished_at: 0,
+            drift: vec![],
+            error: None,
+        };
+
+        let ctxs =
+            Ctx::background_on(state, &self.primary).zip(Ctx::background_on(state, &self.standby));
+        let result = match ctxs {
+            Some((primary, standby)) => {
+                let mut sync = Sync {
+                    replication: self,
+                    primary: primary.without_cache(),
+                    standby: standby.without_cache(),
+                    drift: &mut report.drift,
+                };
+                sync.networks().await
+            }
+            None => Err(ApiError::Unauthorized),
+        };
+
+        report.finished_at = now_millis();
+        if let Err(err) = result {
+            report.error = Some(err.to_string());
+        }
+        if let Ok(mut r) = self.report.write() {
+            *r = Some(report.clone());
+        }
+        report
+    }
+}
+
+impl Report {
+    pub fn drift_count(&self) -> usize {
+        self.drift.len()
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none() && self.drift.iter().all(|d| d.error.is_none())
+    }
+
+    pub fn finished_at(&self) -> i64 {
+        self.finished_at
+    }
+}
+
+/// One replication, collecting the drift while bringing the standby in line.
+struct Sync<'a> {
+    replication: &'a Replication,
+    primary: Ctx,
+    standby: Ctx,
+    drift: &'a mut Vec<Drift>,
+}
+
+impl Sync<'_> {
+    fn apply(&self) -> bool {
+        !self.replication.dry_run
+    }
+
+    fn prune(&self) -> bool {
+        self.replication.prune && !self.replication.dry_run
+    }
+
+    fn push(
+        &mut self,
+        network_id: &str,
+        member_id: Option<&str>,
+        kind: DriftKind,
+        fields: Vec<String>,
+    ) {
+        let syncing = match kind {
+            DriftKind::Extra => self.prune(),
+            _ => self.apply(),
+        };
+        self.drift.push(Drift {
+            network_id: network_id.to_string(),
+            member_id: member_id.map(str::to_string),
+            kind,
+            fields,
+            synced: false,
+            syncing,
+            error: None,
+        });
+    }
+
+    /// Marks the last drift synced.
+    fn synced(&mut self) {
+        if let Some(drift) = self.drift.last_mut() {
+            drift.synced = true;
+            drift.syncing = false;
+        }
+    }
+
+    async fn networks(&mut self) -> Result<()> {
+        let primary_ids = self.primary.get_network_ids().await?;
+        let standby_ids = self
+            .standby
+            .get_network_ids()
+            .await?
+            .into_iter()
+            .collect::<BTreeSet<_>>();
+
+        // a failing network is reported, the others are still replicated.
+        for network_id in &primary_ids {
+            let result = self
+                .network(network_id, standby_ids.contains(network_id))
+                .await;
+            self.record(network_id, result);
+        }
+
+        for network_id in standby_ids.iter().filter(|id| !primary_ids.contains(id)) {
+            self.push(network_id, None, DriftKind::Extra, vec![]);
+            if self.prune() {
+                let result = async {
+                    self.standby.delete_network(network_id).await?;
+                    remove_sidecars(self.standby.work_dir(), network_id)
+                }
+                .await;
+                if result.is_ok() {
+                    self.synced();
+                }
+                self.record(network_id, result);
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the failure of a network on the drift being synced when it
+    /// failed, or on a drift of its own.
+    fn record(&mut self, network_id: &str, result: Result<()>) {
+        let Err(err) = result else {
+            return;
+        };
+        let error = Some(err.to_string());
+        match self.drift.last_mut() {
+            Some(drift) if drift.network_id == network_id && drift.syncing => {
+                drift.syncing = false;
+                drift.error = error;
+            }
+            _ => self.drift.push(Drift {
+                network_id: network_id.to_string(),
+                member_id: None,
+                kind: DriftKind::Unknown,
+                fields: vec![],
+                synced: false,
+                syncing: false,
+                error,
+            }),
+        }
+    }
+
+    async fn network(&mut self, network_id: &str, on_standby: bool) -> Result<()> {
+        let primary = self.primary.get_network(network_id).await?;
+        let standby = match on_standby {
+            true => Some(self.standby.get_network(network_id).await?),
+            false => None,
+        };
+
+        let changed = changed_fields(&primary, standby.as_ref(), &NETWORK_VOLATILE);
+        if standby.is_none() || !changed.is_empty() {
+            let kind = match standby {
+                Some(_) => DriftKind::Config,
+                None => DriftKind::Missing,
+            };
+            self.push(network_id, None, kind, changed.keys().cloned().collect());
+            if self.apply() {
+                // the controller creates the network with the id of the path.
+                self.standby.update_network(network_id, &changed).await?;
+                self.synced();
+            }
+        }
+
+        let read = |ctx: &Ctx| NetworkPalyload::read_from_file(ctx.work_dir(), network_id).ok();
+        let metadata = read(&self.primary).unwrap_or_default();
+        let standby_metadata = read(&self.standby).unwrap_or_default();
+        if metadata.metadata() != standby_metadata.metadata() {
+            self.push(network_id, None, DriftKind::Metadata, vec![]);
+            if self.apply() {
+                let network = NetworkPalyload {
+                    id: Some(network_id.to_string()),
+                    ..metadata
+                };
+                network.write_to_file(self.standby.work_dir())?;
+                self.synced();
+            }
+        }
+
+        self.members(network_id, on_standby).await
+    }
+
+    async fn members(&mut self, network_id: &str, on_standby: bool) -> Result<()> {
+        let primary = fetch_members(&self.primary, network_id).await?;
+        let standby = match on_standby {
+            true => fetch_members(&self.standby, network_id).await?,
+            false => Default::default(),
+        };
+
+        for (member_id, config) in &primary {
+            let standby_config = standby.get(member_id);
+            let changed = changed_fields(config, standby_config, &MEMBER_VOLATILE);
+            if standby_config.is_none() || !changed.is_empty() {
+                let kind = match standby_config {
+                    Some(_) => DriftKind::Config,
+                    None => DriftKind::Missing,
+                };
+                self.push(
+                    network_id,
+                    Some(member_id),
+                    kind,
+                    changed.keys().cloned().collect(),
+                );
+                if self.apply() {
+                    self.standby
+                        .update_member(network_id, member_id, &changed)
+                        .await?;
+                    self.synced();
+                }
+            }
+
+            let read = |ctx: &Ctx| {
+                MemberPayload::read_from_file(&member_file_path(
+                    ctx.work_dir(),
+                    network_id,
+                    member_id,
+                ))
+                .ok()
+            };
+            let metadata = read(&self.primary).unwrap_or_default();
+            let standby_metadata = read(&self.standby).unwrap_or_default();
+            if metadata.metadata() != standby_metadata.metadata() {
+                self.push(network_id, Some(member_id), DriftKind::Metadata, vec![]);
+                if self.apply() {
+                    let member = MemberPayload {
+                        network_id: Some(network_id.to_string()),
+                        node_id: Some(member_id.to_string()),
+                        ..serde_json::from_value(metadata.metadata())?
+                    };
+                    member.write_to_file(&member_file_path(
+                        self.standby.work_dir(),
+                        network_id,
+                        member_id,
+                    ))?;
+                    self.synced();
+                }
+            }
+        }
+
+        for member_id in standby.keys().filter(|id| !primary.contains_key(*id)) {
+            self.push(network_id, Some(member_id), DriftKind::Extra, vec![]);
+            if self.prune() {
+                self.standby.delete_member(network_id, member_id).await?;
+                let file_path = member_file_path(self.standby.work_dir(), network_id, member_id);
+                match std::fs::remove_file(&file_path) {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(ApiError::Sidecar(file_path, err))
+                    }
+                    _ => (),
+                }
+                self.synced();
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn fetch_members(
+    ctx: &Ctx,
+    network_id: &str,
+) -> Result<BTreeMap<String, Map<String, Value>>> {
+    let member_ids = ctx.get_member_ids(network_id).await?;
+    stream::iter(member_ids.into_iter().map(|(member_id, _)| member_id))
+        .map(|member_id| async move {
+            let member = ctx.get_member(network_id, &member_id).await?;
+            Ok::<_, ApiError>((member_id, member))
+        })
+        .buffer_unordered(FETCH_CONCURRENCY)
+        .try_collect()
+        .await
+}
+
+/// The fields of `primary` that `standby` lacks or has another value of, and
+/// the fields only `standby` has, cleared, except for the `volatile` ones.
+fn changed_fields(
+    primary: &Map<String, Value>,
+    standby: Option<&Map<String, Value>>,
+    volatile: &[&str],
+) -> Map<String, Value> {
+    let mut changed = primary
+        .iter()
+        .filter(|(key, _)| !volatile.contains(&key.as_str()))
+        .filter(|(key, value)| standby.is_none_or(|s| s.get(*key) != Some(value)))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect::<Map<_, _>>();
+    for (key, value) in standby.into_iter().flatten() {
+        if !primary.contains_key(key) && !volatile.contains(&key.as_str()) {
+            changed.insert(key.clone(), cleared(key, value).unwrap_or(Value::Null));
+        }
+    }
+    changed
+}
+
+/// Replicates at every interval, the first time on start.
+pub fn spawn_replication(state: SharedState) {
+    let Some(replication) = state.replication.as_ref() else {
+        return;
+    };
+    let interval = replication.interval;
+
+    state.tasks.clone().spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => (),
+                _ = state.shutdown.cancelled() => break,
+            }
+
+            let Some(replication) = state.replication.as_ref() else {
+                break;
+            };
+            let report = replication.run(&state).await;
+            match &report.error {
+                Some(err) => log::warn!(
+                    "replication of {} to {} failed: {}",
+                    report.primary,
+                    report.standby,
+                    err
+                ),
+                None if !report.drift.is_empty() => log::info!(
+                    "replication of {} to {} found {} differences{}.",
+                    report.primary,
+                    report.standby,
+                    report.drift.len(),
+                    if report.dry_run { ", not synced" } else { "" }
+                ),
+                None => (),
+            }
+        }
+    });
+}
+
+/// The replication, if the token of the request is accepted by the primary.
+async fn authorize<'a>(state: &'a SharedState, headers: &HeaderMap) -> Result<&'a Replication> {
+    let replication = state
+        .replication
+        .as_ref()
+        .ok_or(ApiError::ReplicationNotConfigured)?;
+    Ctx::for_request(state, headers, replication.primary.clone())
+        .authorize()
+        .await?;
+    Ok(replication)
+}
+
+/// The report of the last replication, `null` before the first one finished.
+#[utoipa::path(
+    get,
+    path = "/replication",
+    tag = "replication",
+    responses((status = 200, body = Option<Report>), (status = 404)),
+)]
+async fn get_replication(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Result<Json<Option<Report>>> {
+    let replication = authorize(&state, &headers).await?;
+    Ok(Json(replication.report()))
+}
+
+/// Replicates now, and returns the report.
+#[utoipa::path(
+    post,
+    path = "/replication",
+    tag = "replication",
+    responses((status = 200, body = Report), (status = 404)),
+)]
+async fn post_replication(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Result<Json<Report>> {
+    let replication = authorize(&state, &headers).await?;
+    Ok(Json(replication.run(&state).await))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{changed_fields, NETWORK_VOLATILE};
+
+    #[test]
+    fn test_changed_fields() {
+        let primary = json!({
+            "id": "8056c2e21c000001",
+            "revision": 7,
+            "name": "office",
+            "mtu": 2800,
+            "routes": [{ "target": "10.1.0.0/16" }],
+        });
+        let standby = json!({
+            "id": "8056c2e21c000001",
+            "revision": 3,
+            "name": "office",
+            "mtu": 1280,
+        });
+        let primary = primary.as_object().unwrap();
+
+        let changed = changed_fields(primary, standby.as_object(), &NETWORK_VOLATILE);
+        let keys = changed.keys().collect::<Vec<_>>();
+        assert_eq!(keys, ["mtu", "routes"]);
+
+        let changed = changed_fields(primary, None, &NETWORK_VOLATILE);
+        assert_eq!(changed.len(), 3);
+        assert!(changed.get("id").is_none());
+
+        // removed on the primary
+        let standby = json!({
+            "id": "8056c2e21c000001",
+            "name": "office",
+            "mtu": 2800,
+            "routes": [{ "target": "10.1.0.0/16" }],
+            "dns": { "domain": "office.lan", "servers": ["10.1.0.1"] },
+            "creationTime": 1700000000000u64,
+        });
+        let changed = changed_fields(primary, standby.as_object(), &NETWORK_VOLATILE);
+        assert_eq!(
+            serde_json::Value::Object(changed),
+            json!({ "dns": { "domain": "", "servers": [] } })
+        );
+    }
+}
@@ -0,0 +1,447 @@
+//! Network templates stored in the work dir, to create networks following
+//! the same conventions, e.g. one per site with its own subnet.
+
+use std::{
+    collections::BTreeSet,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path as FsPath, PathBuf},
+};
+
+use axum::{
+    extract::Path,
+    routing::{get, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use utoipa::ToSchema;
+
+use super::{
+    bulk::{addr_bits, addr_to_u128, mask, parse_cidr},
+    ctx::Ctx,
+    extract::Json,
+    network::{add_network, NetworkPalyload},
+    ApiError, FieldError, Result, SharedState,
+};
+use crate::log;
+
+#[inline]
+pub fn routes() -> Router<SharedState> {
+    Router::new()
+        .route("/template", get(get_templates))
+        .route(
+            "/template/:name",
+            get(get_template).put(put_template).delete(delete_template),
+        )
+        .route("/template/:name/network", post(create_from_template))
+}
+
+/// A network to create with variables, `${name}` in the strings of `network`
+/// is replaced by the value of the variable.
+///
+/// For a variable holding a cidr such as `10.1.0.0/24`, `${name.network}`,
+/// `${name.first}`, `${name.last}` and `${name.prefix}` are also defined, e.g.
+/// for `ipAssignmentPools`. A string that is only a variable takes its JSON
+/// value, e.g. `"mtu": "${mtu}"` with a number.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct Template {
+    /// set from the path.
+    #[serde(default)]
+    name: String,
+    description: Option<String>,
+    /// the variables with their default value, `null` for required ones.
+    #[serde(default)]
+    variables: Map<String, Value>,
+    /// the network to create, as sent to `POST /network`.
+    network: NetworkPalyload,
+}
+
+#[derive(Debug, Deserialize, Default, ToSchema)]
+struct Instantiate {
+    /// values of the variables, the defaults of the template otherwise.
+    #[serde(default)]
+    variables: Map<String, Value>,
+}
+
+/// The templates, by name.
+#[utoipa::path(
+    get,
+    path = "/template",
+    tag = "template",
+    responses((status = 200, body = [Template])),
+)]
+async fn get_templates(ctx: Ctx) -> Result<Json<Vec<Template>>> {
+    ctx.authorize().await?;
+    let dir = templates_dir(ctx.instance_work_dir());
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Json(vec![])),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut templates = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        let Some(name) = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_suffix(".json"))
+        else {
+            continue;
+        };
+        match read_template(ctx.instance_work_dir(), name) {
+            Ok(template) => templates.push(template),
+            Err(err) => log::warn!("skipping template {:?}: {}", path, err),
+        }
+    }
+    templates.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Json(templates))
+}
+
+#[utoipa::path(
+    get,
+    path = "/template/{name}",
+    tag = "template",
+    params(("name" = String, Path)),
+    responses((status = 200, body = Template), (status = 404)),
+)]
+async fn get_template(ctx: Ctx, Path(name): Path<String>) -> Result<Json<Template>> {
+    ctx.authorize().await?;
+    Ok(Json(read_template(ctx.instance_work_dir(), &name)?))
+}
+
+/// Creates or replaces a template.
+#[utoipa::path(
+    put,
+    path = "/template/{name}",
+    tag = "template",
+    params(("name" = String, Path)),
+    request_body = Template,
+    responses((status = 200, body = Template), (status = 400)),
+)]
+async fn put_template(
+    ctx: Ctx,
+    Path(name): Path<String>,
+    Json(mut template): Json<Template>,
+) -> Result<Json<Template>> {
+    ctx.authorize().await?;
+    validate_name(&name)?;
+    template.name = name;
+
+    let mut network = serde_json::to_value(&template.network)?;
+    let undeclared = placeholders(&network)
+        .iter()
+        .map(|p| variable_base(p).to_string())
+        .filter(|name| !template.variables.contains_key(name))
+        .collect::<BTreeSet<_>>();
+    if !undeclared.is_empty() {
+        return Err(ApiError::InvalidFields(
+            undeclared
+                .into_iter()
+                .map(|name| FieldError {
+                    field: format!("variables.{}", name),
+                    message: "is used by the network but not declared".to_string(),
+                })
+                .collect(),
+        ));
+    }
+
+    // the controller assigns the id.
+    if let Some(network) = network.as_object_mut() {
+        network.remove("id");
+        if let Some(config) = network.get_mut("config").and_then(Value::as_object_mut) {
+            config.remove("id");
+            config.remove("nwid");
+        }
+    }
+    template.network = serde_json::from_value(network)?;
+
+    let file_path = template_file_path(ctx.instance_work_dir(), &template.name);
+    if let Some(dir) = file_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&file_path, serde_json::to_vec_pretty(&template)?)?;
+    Ok(Json(template))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/template/{name}",
+    tag = "template",
+    params(("name" = String, Path)),
+    responses((status = 200, body = Template), (status = 404)),
+)]
+async fn delete_template(ctx: Ctx, Path(name): Path<String>) -> Result<Json<Template>> {
+    ctx.authorize().await?;
+    let template = read_template(ctx.instance_work_dir(), &name)?;
+    std::fs::remove_file(template_file_path(ctx.instance_work_dir(), &name))?;
+    Ok(Json(template))
+}
+
+/// Creates a network from a template, with the given variables.
+#[utoipa::path(
+    post,
+    path = "/template/{name}/network",
+    tag = "template",
+    params(("name" = String, Path)),
+    request_body = Instantiate,
+    responses((status = 200, body = NetworkPalyload), (status = 400), (status = 404)),
+)]
+async fn create_from_template(
+    ctx: Ctx,
+    Path(name): Path<String>,
+    Json(request): Json<Instantiate>,
+) -> Result<Json<NetworkPalyload>> {
+    ctx.authorize().await?;
+    let template = read_template(ctx.instance_work_dir(), &name)?;
+    let network = template.instantiate(request.variables)?;
+    Ok(Json(add_network(&ctx, network).await?))
+}
+
+impl Template {
+    /// The network with the variables substituted.
+    fn instantiate(&self, values: Map<String, Value>) -> Result<NetworkPalyload> {
+        let mut fields = vec![];
+        let mut variables = Map::new();
+        for (name, value) in values {
+            if !self.variables.contains_key(&name) {
+                fields.push(FieldError {
+                    field: format!("variables.{}", name),
+                    message: "is not a variable of the template".to_string(),
+                });
+                continue;
+            }
+            variables.insert(name, value);
+        }
+        for (name, default) in &self.variables {
+            if !variables.contains_key(name) && !default.is_null() {
+                variables.insert(name.clone(), default.clone());
+            }
+        }
+
+        for (name, value) in variables.clone() {
+            if let Some(derived) = value.as_str().and_then(|s| subnet_variables(&name, s)) {
+                variables.extend(derived);
+            }
+        }
+
+        let mut network = serde_json::to_value(&self.network)?;
+        let mut missing = BTreeSet::new();
+        substitute(&mut network, &variables, &mut missing);
+        let missing = missing
+            .iter()
+            .map(|p| variable_base(p))
+            .collect::<BTreeSet<_>>();
+        // a derived placeholder is only missing when its variable is not a cidr.
+        fields.extend(missing.into_iter().map(|name| FieldError {
+            field: format!("variables.{}", name),
+            message: if variables.contains_key(name) {
+                "must be a cidr".to_string()
+            } else {
+                "is required".to_string()
+            },
+        }));
+        if !fields.is_empty() {
+            return Err(ApiError::InvalidFields(fields));
+        }
+
+        serde_json::from_value(network)
+            .map_err(|err| ApiError::BadRequest(format!("network of template: {}", err)))
+    }
+}
+
+/// Templates of the instance, shared by all controllers.
+fn templates_dir(work_dir: &FsPath) -> PathBuf {
+    work_dir.join("templates.d")
+}
+
+fn template_file_path(work_dir: &FsPath, name: &str) -> PathBuf {
+    templates_dir(work_dir).join(format!("{}.json", name))
+}
+
+fn read_template(work_dir: &FsPath, name: &str) -> Result<Template> {
+    validate_name(name).map_err(|_| ApiError::TemplateNotFound(name.to_string()))?;
+    let bytes = match std::fs::read(template_file_path(work_dir, name)) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(ApiError::TemplateNotFound(name.to_string()))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let mut template: Template = serde_json::from_slice(&bytes)?;
+    template.name = name.to_string();
+    Ok(template)
+}
+
+/// Names are used as file names.
+fn validate_name(name: &str) -> Result<()> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || "-_".contains(c);
+    if name.is_empty() || !name.chars().all(valid) {
+        return Err(ApiError::invalid(
+            "name",
+            "only letters, digits, `-` and `_` are allowed",
+        ));
+    }
+    Ok(())
+}
+
+/// The variable of `subnet.first`, `subnet`.
+fn variable_base(placeholder: &str) -> &str {
+    placeholder.split('.').next().unwrap_or(placeholder)
+}
+
+/// The variables derived from variable `name`, `None` unless it is a cidr.
+fn subnet_variables(name: &str, cidr: &str) -> Option<Map<String, Value>> {
+    let (addr, prefix) = parse_cidr(name, cidr).ok()?;
+    let bits = addr_bits(&addr);
+    let network = addr_to_u128(addr) & mask(bits, prefix);
+    let last = network | (!mask(bits, prefix) & mask(bits, bits));
+    // leave out the network and broadcast addresses when there are hosts between.
+    let (first, last) = match last - network {
+        0 | 1 => (network, last),
+        _ => (network + 1, last - 1),
+    };
+    let to_addr = |n: u128| -> IpAddr {
+        match addr {
+            IpAddr::V4(_) => Ipv4Addr::from(n as u32).into(),
+            IpAddr::V6(_) => Ipv6Addr::from(n).into(),
+        }
+    };
+
+    Some(Map::from_iter([
+        (
+            format!("{name}.network"),
+            to_addr(network).to_string().into(),
+        ),
+        (format!("{name}.first"), to_addr(first).to_string().into()),
+        (format!("{name}.last"), to_addr(last).to_string().into()),
+        (format!("{name}.prefix"), prefix.into()),
+    ]))
+}
+
+/// The `${...}` placeholders in the strings of `value`.
+fn placeholders(value: &Value) -> BTreeSet<String> {
+    let mut found = BTreeSet::new();
+    substitute(&mut value.clone(), &Map::new(), &mut found);
+    found
+}
+
+/// Replaces the placeholders in the strings of `value`, the ones without a
+/// variable are added to `missing`.
+fn substitute(value: &mut Value, variables: &Map<String, Value>, missing: &mut BTreeSet<String>) {
+    match value {
+        Value::String(s) => {
+            if let Some(name) = s
+                .strip_prefix("${")
+                .and_then(|s| s.strip_suffix('}'))
+                .filter(|name| !name.contains('}'))
+            {
+                match variables.get(name) {
+                    Some(variable) => *value = variable.clone(),
+                    None => {
+                        missing.insert(name.to_string());
+                    }
+                }
+                return;
+            }
+
+            let mut out = String::with_capacity(s.len());
+            let mut rest = s.as_str();
+            while let Some(start) = rest.find("${") {
+                let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+                    break;
+                };
+                out.push_str(&rest[..start]);
+                let name = &rest[start + 2..end];
+                match variables.get(name) {
+                    Some(Value::String(variable)) => out.push_str(variable),
+                    Some(variable) => out.push_str(&variable.to_string()),
+                    None => {
+                        missing.insert(name.to_string());
+                        out.push_str(&rest[start..=end]);
+                    }
+                }
+                rest = &rest[end + 1..];
+            }
+            out.push_str(rest);
+            *s = out;
+        }
+        Value::Array(values) => values
+            .iter_mut()
+            .for_each(|v| substitute(v, variables, missing)),
+        Value::Object(values) => values
+            .values_mut()
+            .for_each(|v| substitute(v, variables, missing)),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Map};
+
+    use super::{placeholders, Template};
+
+    #[test]
+    fn test_instantiate() {
+        let template = serde_json::from_value::<Template>(json!({
+            "variables": { "site": null, "subnet": null, "mtu": 2800 },
+            "network": {
+                "description": "network of ${site}",
+                "config": {
+                    "name": "${site}-lan",
+                    "mtu": "${mtu}",
+                    "ipAssignmentPools": [
+                        { "ipRangeStart": "${subnet.first}", "ipRangeEnd": "${subnet.last}" }
+                    ],
+                    "routes": [{ "target": "${subnet}", "via": null }],
+                },
+            },
+        }))
+        .unwrap();
+
+        assert_eq!(
+            placeholders(&serde_json::to_value(&template.network).unwrap())
+                .into_iter()
+                .collect::<Vec<_>>(),
+            ["mtu", "site", "subnet", "subnet.first", "subnet.last"]
+        );
+
+        let variables = json!({ "site": "berlin", "subnet": "10.1.0.0/24" });
+        let variables = serde_json::from_value::<Map<_, _>>(variables).unwrap();
+        let network = template.instantiate(variables).unwrap();
+        let config = serde_json::to_value(network.config).unwrap();
+        assert_eq!(network.description.as_deref(), Some("network of berlin"));
+        assert_eq!(config["name"], "berlin-lan");
+        assert_eq!(config["mtu"], 2800);
+        assert_eq!(config["ipAssignmentPools"][0]["ipRangeStart"], "10.1.0.1");
+        assert_eq!(config["ipAssignmentPools"][0]["ipRangeEnd"], "10.1.0.254");
+        assert_eq!(config["routes"][0]["target"], "10.1.0.0/24");
+
+        let variables = json!({ "site": "HQ/Berlin", "subnet": "10.1.0.0/24" });
+        let variables = serde_json::from_value::<Map<_, _>>(variables).unwrap();
+        let network = template.instantiate(variables).unwrap();
+        assert_eq!(network.description.as_deref(), Some("network of HQ/Berlin"));
+
+        let variables = json!({ "site": "berlin", "vlan": 3 });
+        let variables = serde_json::from_value::<Map<_, _>>(variables).unwrap();
+        let fields = match template.instantiate(variables) {
+            Err(super::ApiError::InvalidFields(fields)) => fields,
+            _ => panic!("expected invalid fields"),
+        };
+        let fields = fields.iter().map(|f| f.field.as_str()).collect::<Vec<_>>();
+        assert_eq!(fields, ["variables.vlan", "variables.subnet"]);
+
+        let variables = json!({ "site": "berlin", "subnet": "10.1.0.0" });
+        let variables = serde_json::from_value::<Map<_, _>>(variables).unwrap();
+        let fields = match template.instantiate(variables) {
+            Err(super::ApiError::InvalidFields(fields)) => fields,
+            _ => panic!("expected invalid fields"),
+        };
+        assert_eq!(fields.len(), 1);
+        assert_eq!(fields[0].field, "variables.subnet");
+        assert_eq!(fields[0].message, "must be a cidr");
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use super::{ControllerState, SharedState};
+use crate::log;
+
+/// How often the token file is checked for a rotated token.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A controller token read from a file, e.g. `authtoken.secret`.
+#[derive(Debug, Default)]
+pub struct TokenFile {
+    path: Option<PathBuf>,
+    /// reload failures are only worth a warning for a configured file.
+    configured: bool,
+    token: RwLock<Option<String>>,
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl TokenFile {
+    /// A configured file must be readable, a discovered one may be missing or
+    /// unreadable, e.g. when not running as the zerotier-one user.
+    pub fn load(path: PathBuf, configured: bool) -> io::Result<Self> {
+        let token_file = Self {
+            path: Some(path),
+            configured,
+            ..Default::default()
+        };
+        match token_file.reload() {
+            Ok(_) => (),
+            Err(err) if configured => return Err(err),
+            Err(err) => log::debug!("no token from {:?}: {}", token_file.path.as_deref(), err),
+        }
+        Ok(token_file)
+    }
+
+    pub fn get(&self) -> Option<String> {
+        self.token.read().ok().and_then(|t| t.clone())
+    }
+
+    /// Reads the file again if it was modified, returns whether it was.
+    fn reload(&self) -> io::Result<bool> {
+        let Some(path) = &self.path else {
+            return Ok(false);
+        };
+        let modified = fs::metadata(path)?.modified()?;
+        if self.modified.read().is_ok_and(|m| *m == Some(modified)) {
+            return Ok(false);
+        }
+
+        let token = read_token(path)?;
+        if let Ok(mut t) = self.token.write() {
+            *t = token;
+        }
+        if let Ok(mut m) = self.modified.write() {
+            *m = Some(modified);
+        }
+        Ok(true)
+    }
+}
+
+fn read_token(path: &Path) -> io::Result<Option<String>> {
+    let token = fs::read_to_string(path)?.trim().to_string();
+    Ok((!token.is_empty()).then_some(token))
+}
+
+/// Picks up a rotated token.
+pub fn spawn_reload(state: SharedState, controller: Arc<ControllerState>) {
+    let Some(path) = controller.token.path.clone() else {
+        return;
+    };
+
+    state.tasks.clone().spawn(async move {
+        let token = &controller.token;
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(RELOAD_INTERVAL) => (),
+                _ = state.shutdown.cancelled() => break,
+            }
+            match token.reload() {
+                Ok(true) => log::info!("reloaded token from {:?}", path),
+                Ok(false) => (),
+                Err(err) if token.configured => {
+                    log::warn!("failed to reload token from {:?}: {}", path, err)
+                }
+                Err(_) => (),
+            }
+        }
+    });
+}
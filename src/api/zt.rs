@@ -1,36 +1,75 @@
+use std::time::{Duration, Instant};
+
+use reqwest::{Method, Response, StatusCode};
 use serde_json::{Map, Value};
 
-use super::{ApiError, Ctx, Result};
+use super::{metrics::METRICS, ApiError, Ctx, Result};
+use crate::log;
+
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
 
 impl Ctx {
     pub(super) async fn get_status(&self) -> Result<Value> {
-        let client = self.http_client().clone();
-        let base_url = self.base_url();
-        let token = self.zt1_token().unwrap_or_default();
-        let status = client
-            .get(format!("{}/status", base_url.trim_end_matches('/')))
-            .header("X-ZT1-AUTH", token)
-            .send()
+        let status = self
+            .send(Method::GET, "/status", None)
             .await?
-            .error_for_status()?
             .json()
             .await?;
         Ok(status)
     }
 
-    pub(super) async fn get_network_ids(&self) -> Result<Vec<String>> {
-        let client = self.http_client().clone();
-        let base_url = self.base_url();
-        let token = self.zt1_token().unwrap_or_default();
-        let networks = client
-            .get(format!(
-                "{}/controller/network",
-                base_url.trim_end_matches('/')
-            ))
-            .header("X-ZT1-AUTH", token)
+    /// Status code of `/status`, a response of any status means the
+    /// controller is reachable.
+    pub(super) async fn probe_status(&self) -> Result<StatusCode> {
+        let response = self
+            .http_client()
+            .get(format!("{}/status", self.base_url().trim_end_matches('/')))
+            .header("X-ZT1-AUTH", self.zt1_token().unwrap_or_default())
             .send()
+            .await?;
+        if response.status().is_success() {
+            self.cache()
+                .accept_token(self.zt1_token().unwrap_or_default());
+        }
+        Ok(response.status())
+    }
+
+    /// Checks that the controller accepts the token, for requests served
+    /// without asking the controller.
+    pub(super) async fn authorize(&self) -> Result<()> {
+        match self.probe_status().await? {
+            status if status.is_success() => Ok(()),
+            _ => Err(ApiError::Unauthorized),
+        }
+    }
+
+    /// Status of the controller, `None` when the node is not one.
+    pub(super) async fn get_controller(&self) -> Result<Option<Value>> {
+        match self.send(Method::GET, "/controller", None).await {
+            Ok(response) => Ok(Some(response.json().await?)),
+            Err(err) if err.status() == Some(StatusCode::NOT_FOUND) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Networks this node has joined, not the ones of its controller.
+    pub(super) async fn get_joined_networks(&self) -> Result<Vec<Map<String, Value>>> {
+        let networks = self
+            .send(Method::GET, "/network", None)
+            .await?
+            .json()
+            .await?;
+        Ok(networks)
+    }
+
+    pub(super) async fn get_network_ids(&self) -> Result<Vec<String>> {
+        if let Some(network_ids) = self.cached(|s| s.network_ids.clone()) {
+            return Ok(network_ids);
+        }
+
+        let networks = self
+            .send(Method::GET, "/controller/network", None)
             .await?
-            .error_for_status()?
             .json()
             .await?;
 
@@ -38,19 +77,17 @@ impl Ctx {
     }
 
     pub(super) async fn get_network(&self, network_id: &str) -> Result<Map<String, Value>> {
-        let client = self.http_client().clone();
-        let base_url = self.base_url();
-        let token = self.zt1_token().unwrap_or_default();
+        if let Some(Some(network)) = self.cached(|s| s.networks.get(network_id).cloned()) {
+            return Ok(network);
+        }
 
-        let network = client
-            .get(format!(
-                "{}/controller/network/{network_id}",
-                base_url.trim_end_matches('/')
-            ))
-            .header("X-ZT1-AUTH", token)
-            .send()
-            .await?
-            .error_for_status()
+        let network = self
+            .send(
+                Method::GET,
+                &format!("/controller/network/{network_id}"),
+                None,
+            )
+            .await
             .map_err(|err| {
                 map_not_found(err, || ApiError::NetworkNotFound(network_id.to_string()))
             })?
@@ -65,26 +102,20 @@ impl Ctx {
         network_id: &str,
         network: &Map<String, Value>,
     ) -> Result<Map<String, Value>> {
-        let client = self.http_client().clone();
-        let base_url = self.base_url();
-        let token = self.zt1_token().unwrap_or_default();
-
-        let network = client
-            .post(format!(
-                "{}/controller/network/{network_id}",
-                base_url.trim_end_matches('/')
-            ))
-            .header("X-ZT1-AUTH", token)
-            .header("Content-Type", "application/json")
-            .json(network)
-            .send()
-            .await?
-            .error_for_status()
+        let network: Map<String, Value> = self
+            .send(
+                Method::POST,
+                &format!("/controller/network/{network_id}"),
+                Some(network),
+            )
+            .await
             .map_err(|err| {
                 map_not_found(err, || ApiError::NetworkNotFound(network_id.to_string()))
             })?
             .json()
             .await?;
+
+        self.cache().put_network(network_id, network.clone());
         Ok(network)
     }
 
@@ -92,10 +123,6 @@ impl Ctx {
         &self,
         network: &Map<String, Value>,
     ) -> Result<Map<String, Value>> {
-        let client = self.http_client().clone();
-        let base_url = self.base_url();
-        let token = self.zt1_token().unwrap_or_default();
-
         if let Some(node_id) = self
             .get_status()
             .await?
@@ -103,21 +130,19 @@ impl Ctx {
             .and_then(|s| s.get("address"))
             .and_then(|s| s.as_str())
         {
-            let network = client
-                .post(format!(
-                    "{}/controller/network/{}______",
-                    base_url.trim_end_matches('/'),
-                    node_id
-                ))
-                .header("X-ZT1-AUTH", token)
-                .header("Content-Type", "application/json")
-                .json(network)
-                .send()
+            let network: Map<String, Value> = self
+                .send(
+                    Method::POST,
+                    &format!("/controller/network/{node_id}______"),
+                    Some(network),
+                )
                 .await?
-                .error_for_status()?
                 .json()
                 .await?;
 
+            if let Some(network_id) = network.get("id").and_then(|x| x.as_str()) {
+                self.cache().put_network(network_id, network.clone());
+            }
             Ok(network)
         } else {
             Err(ApiError::Zerotier(
@@ -127,43 +152,35 @@ impl Ctx {
     }
 
     pub(super) async fn delete_network(&self, network_id: &str) -> Result<Map<String, Value>> {
-        let client = self.http_client().clone();
-        let base_url = self.base_url();
-        let token = self.zt1_token().unwrap_or_default();
-
-        let network = client
-            .delete(format!(
-                "{}/controller/network/{network_id}",
-                base_url.trim_end_matches('/')
-            ))
-            .header("X-ZT1-AUTH", token)
-            .send()
-            .await?
-            .error_for_status()
+        let network = self
+            .send(
+                Method::DELETE,
+                &format!("/controller/network/{network_id}"),
+                None,
+            )
+            .await
             .map_err(|err| {
                 map_not_found(err, || ApiError::NetworkNotFound(network_id.to_string()))
             })?
             .json()
             .await?;
 
+        self.cache().remove_network(network_id);
         Ok(network)
     }
 
     pub(super) async fn get_member_ids(&self, network_id: &str) -> Result<Map<String, Value>> {
-        let client = self.http_client().clone();
-        let base_url = self.base_url();
-        let token = self.zt1_token().unwrap_or_default();
+        if let Some(Some(member_ids)) = self.cached(|s| s.member_ids(network_id)) {
+            return Ok(member_ids);
+        }
 
-        let bytes = client
-            .get(format!(
-                "{}/controller/network/{}/member",
-                base_url.trim_end_matches('/'),
-                network_id
-            ))
-            .header("X-ZT1-AUTH", token)
-            .send()
+        let bytes = self
+            .send(
+                Method::GET,
+                &format!("/controller/network/{network_id}/member"),
+                None,
+            )
             .await?
-            .error_for_status()?
             .bytes()
             .await?;
 
@@ -186,26 +203,22 @@ impl Ctx {
         network_id: &str,
         member_id: &str,
     ) -> Result<Map<String, Value>> {
-        let client = self.http_client().clone();
-        let base_url = self.base_url();
-        let token = self.zt1_token().unwrap_or_default();
+        if let Some(Some(member)) = self.cached(|s| s.member(network_id, member_id).cloned()) {
+            return Ok(member);
+        }
 
-        let network = client
-            .get(format!(
-                "{}/controller/network/{}/member/{}",
-                base_url.trim_end_matches('/'),
-                network_id,
-                member_id
-            ))
-            .header("X-ZT1-AUTH", token)
-            .send()
-            .await?
-            .error_for_status()
+        let member = self
+            .send(
+                Method::GET,
+                &format!("/controller/network/{network_id}/member/{member_id}"),
+                None,
+            )
+            .await
             .map_err(|err| map_not_found(err, || ApiError::MemberNotFound(member_id.to_string())))?
             .json()
             .await?;
 
-        Ok(network)
+        Ok(member)
     }
 
     pub(super) async fn update_member(
@@ -214,28 +227,20 @@ impl Ctx {
         member_id: &str,
         member: &Map<String, Value>,
     ) -> Result<Map<String, Value>> {
-        let client = self.http_client().clone();
-        let base_url = self.base_url();
-        let token = self.zt1_token().unwrap_or_default();
-
-        let network = client
-            .post(format!(
-                "{}/controller/network/{}/member/{}",
-                base_url.trim_end_matches('/'),
-                network_id,
-                member_id
-            ))
-            .header("X-ZT1-AUTH", token)
-            .header("Content-Type", "application/json")
-            .json(member)
-            .send()
-            .await?
-            .error_for_status()
+        let member: Map<String, Value> = self
+            .send(
+                Method::POST,
+                &format!("/controller/network/{network_id}/member/{member_id}"),
+                Some(member),
+            )
+            .await
             .map_err(|err| map_not_found(err, || ApiError::MemberNotFound(member_id.to_string())))?
             .json()
             .await?;
 
-        Ok(network)
+        self.cache()
+            .put_member(network_id, member_id, member.clone());
+        Ok(member)
     }
 
     pub(super) async fn delete_member(
@@ -243,64 +248,113 @@ impl Ctx {
         network_id: &str,
         member_id: &str,
     ) -> Result<Map<String, Value>> {
-        let client = self.http_client().clone();
-        let base_url = self.base_url();
-        let token = self.zt1_token().unwrap_or_default();
-
-        let network = client
-            .delete(format!(
-                "{}/controller/network/{}/member/{}",
-                base_url.trim_end_matches('/'),
-                network_id,
-                member_id
-            ))
-            .header("X-ZT1-AUTH", token)
-            .send()
-            .await?
-            .error_for_status()
+        let member = self
+            .send(
+                Method::DELETE,
+                &format!("/controller/network/{network_id}/member/{member_id}"),
+                None,
+            )
+            .await
             .map_err(|err| map_not_found(err, || ApiError::MemberNotFound(member_id.to_string())))?
             .json()
             .await?;
 
-        Ok(network)
+        self.cache().remove_member(network_id, member_id);
+        Ok(member)
     }
 
     pub(super) async fn get_peers(&self) -> Result<Vec<Map<String, Value>>> {
-        let client = self.http_client().clone();
-        let base_url = self.base_url();
-        let token = self.zt1_token().unwrap_or_default();
+        if let Some(peers) = self.cached(|s| s.peers.values().cloned().collect()) {
+            return Ok(peers);
+        }
 
-        let network = client
-            .get(format!("{}/peer", base_url.trim_end_matches('/')))
-            .header("X-ZT1-AUTH", token)
-            .send()
-            .await?
-            .error_for_status()?
-            .json()
-            .await?;
-        Ok(network)
+        let peers = self.send(Method::GET, "/peer", None).await?.json().await?;
+        Ok(peers)
     }
 
     pub(super) async fn get_peer(&self, address: &str) -> Result<Map<String, Value>> {
-        let client = self.http_client().clone();
-        let base_url = self.base_url();
-        let token = self.zt1_token().unwrap_or_default();
+        // peers missing from a valid snapshot are offline, no need to ask again.
+        if let Some(peer) = self.cached(|s| s.peers.get(address).cloned()) {
+            return peer.ok_or_else(|| ApiError::PeerNotFound(address.to_string()));
+        }
 
-        let network = client
-            .get(format!(
-                "{}/peer/{}",
-                base_url.trim_end_matches('/'),
-                address
-            ))
-            .header("X-ZT1-AUTH", token)
-            .send()
-            .await?
-            .error_for_status()
+        let peer = self
+            .send(Method::GET, &format!("/peer/{address}"), None)
+            .await
             .map_err(|err| map_not_found(err, || ApiError::PeerNotFound(address.to_string())))?
             .json()
             .await?;
 
-        Ok(network)
+        Ok(peer)
+    }
+
+    /// Sends a request to the controller, at most `ApiState::limiter` at a
+    /// time. GET requests are retried with backoff on transient failures.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&Map<String, Value>>,
+    ) -> reqwest::Result<Response> {
+        let token = self.zt1_token().unwrap_or_default();
+        let url = format!("{}{path}", self.base_url().trim_end_matches('/'));
+        let retries = if method == Method::GET {
+            self.retries()
+        } else {
+            0
+        };
+
+        let mut attempt = 0;
+        let response = loop {
+            let mut request = self
+                .http_client()
+                .request(method.clone(), &url)
+                .header("X-ZT1-AUTH", token);
+
+            if let Some(body) = body {
+                request = request
+                    .header("Content-Type", "application/json")
+                    .json(body);
+            }
+
+            let result = {
+                let _permit = self.limiter().acquire().await;
+                let start = Instant::now();
+                let result = request.send().await.and_then(|r| r.error_for_status());
+                METRICS.observe_controller(method.as_str(), path, start.elapsed(), &result);
+                result
+            };
+
+            match result {
+                Err(err) if attempt < retries && is_transient(&err) => {
+                    let backoff = RETRY_BACKOFF * 2u32.pow(attempt);
+                    log::debug!(
+                        "{} {} failed, retrying in {:?}: {}",
+                        method,
+                        path,
+                        backoff,
+                        err
+                    );
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                result => break result?,
+            }
+        };
+
+        self.cache().accept_token(token);
+        Ok(response)
+    }
+}
+
+fn is_transient(err: &reqwest::Error) -> bool {
+    use reqwest::StatusCode;
+    match err.status() {
+        Some(status) => matches!(
+            status,
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+        ),
+        None => err.is_timeout() || err.is_connect(),
     }
 }
 
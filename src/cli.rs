@@ -0,0 +1,395 @@
+//! Subcommands managing the controller without the web UI.
+
+use std::sync::Arc;
+
+use clap::Subcommand;
+use serde_json::Value;
+
+use crate::{
+    api::{
+        cli::Controller,
+        doctor::{self, Status},
+        ApiError, ApiState,
+    },
+    config::Args,
+};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// manage networks.
+    #[command(subcommand)]
+    Network(NetworkCommand),
+    /// manage the members of a network.
+    #[command(subcommand)]
+    Member(MemberCommand),
+    /// list the peers of the controller.
+    #[command(subcommand)]
+    Peer(PeerCommand),
+    /// check the work dir, the token and the controller, and report problems.
+    Doctor,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum NetworkCommand {
+    /// list networks.
+    List,
+    /// show a network.
+    Show { network_id: String },
+    /// create a network.
+    Create {
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long)]
+        description: Option<String>,
+    },
+    /// delete a network.
+    Delete { network_id: String },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum MemberCommand {
+    /// list the members of a network.
+    List { network_id: String },
+    /// authorize a member.
+    Authorize {
+        network_id: String,
+        member_id: String,
+    },
+    /// deauthorize a member.
+    Deauthorize {
+        network_id: String,
+        member_id: String,
+    },
+    /// set the name of a member.
+    Rename {
+        network_id: String,
+        member_id: String,
+        name: String,
+    },
+    /// delete a member.
+    Delete {
+        network_id: String,
+        member_id: String,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum PeerCommand {
+    /// list peers.
+    List,
+}
+
+/// Runs the subcommand of `args`, prints its result as a table or JSON and
+/// returns the exit code.
+pub async fn run(args: &Args, state: &Arc<ApiState>) -> i32 {
+    let Some(command) = args.command.clone() else {
+        return 0;
+    };
+    if let Command::Doctor = command {
+        return doctor(args, state).await;
+    }
+
+    match execute(state, command, args.json).await {
+        Ok(output) => {
+            print!("{}", output);
+            0
+        }
+        Err(ApiError::Unauthorized) => {
+            eprintln!("error: unauthorized, a controller token is needed, see --token-file");
+            1
+        }
+        Err(err) => {
+            eprintln!("error: {}", err);
+            1
+        }
+    }
+}
+
+async fn doctor(args: &Args, state: &Arc<ApiState>) -> i32 {
+    let report = doctor::diagnose(state, &args.work_dir_source(), args.token_file()).await;
+
+    if args.json {
+        print!(
+            "{}",
+            to_json(&serde_json::to_value(&report).unwrap_or_default())
+        );
+    } else {
+        let width = report
+            .checks
+            .iter()
+            .map(|c| c.name.len())
+            .max()
+            .unwrap_or(0);
+        for check in &report.checks {
+            let status = match check.status {
+                Status::Ok => "ok",
+                Status::Warn => "warn",
+                Status::Fail => "FAIL",
+                Status::Skip => "skip",
+            };
+            println!(
+                "{:4}  {:width$}  {}",
+                status,
+                check.name,
+                check.message,
+                width = width
+            );
+            if let Some(hint) = &check.hint {
+                println!("{:4}  {:width$}  -> {}", "", "", hint, width = width);
+            }
+        }
+    }
+
+    i32::from(report.failed())
+}
+
+async fn execute(state: &Arc<ApiState>, command: Command, json: bool) -> Result<String, ApiError> {
+    let controller = Controller::new(state)?;
+
+    let (value, format): (Value, fn(&[Value]) -> String) = match command {
+        Command::Network(command) => match command {
+            NetworkCommand::List => (controller.networks().await?, network_table),
+            NetworkCommand::Show { network_id } => {
+                let network = controller.network(&network_id).await?;
+                return Ok(if json {
+                    to_json(&network)
+                } else {
+                    network_details(&network)
+                });
+            }
+            NetworkCommand::Create { name, description } => (
+                controller.create_network(name, description).await?,
+                network_table,
+            ),
+            NetworkCommand::Delete { network_id } => {
+                (controller.delete_network(&network_id).await?, network_table)
+            }
+        },
+        Command::Member(command) => match command {
+            MemberCommand::List { network_id } => {
+                (controller.members(&network_id).await?, member_table)
+            }
+            MemberCommand::Authorize {
+                network_id,
+                member_id,
+            } => (
+                controller
+                    .authorize_member(&network_id, &member_id, true)
+                    .await?,
+                member_table,
+            ),
+            MemberCommand::Deauthorize {
+                network_id,
+                member_id,
+            } => (
+                controller
+                    .authorize_member(&network_id, &member_id, false)
+                    .await?,
+                member_table,
+            ),
+            MemberCommand::Rename {
+                network_id,
+                member_id,
+                name,
+            } => (
+                controller
+                    .rename_member(&network_id, &member_id, name)
+                    .await?,
+                member_table,
+            ),
+            MemberCommand::Delete {
+                network_id,
+                member_id,
+            } => (
+                controller.delete_member(&network_id, &member_id).await?,
+                member_table,
+            ),
+        },
+        Command::Peer(PeerCommand::List) => (controller.peers().await?, peer_table),
+        Command::Doctor => unreachable!("doctor does not need a token"),
+    };
+
+    Ok(match (json, value) {
+        (true, value) => to_json(&value),
+        (false, Value::Array(rows)) => format(&rows),
+        (false, row) => format(&[row]),
+    })
+}
+
+fn to_json(value: &Value) -> String {
+    format!("{:#}\n", value)
+}
+
+fn network_table(networks: &[Value]) -> String {
+    table(
+        &["ID", "NAME", "PRIVATE", "AUTHORIZED", "ONLINE", "TOTAL"],
+        networks
+            .iter()
+            .map(|n| {
+                vec![
+                    text(&n["id"]),
+                    text(&n["config"]["name"]),
+                    text(&n["config"]["private"]),
+                    text(&n["authorizedMemberCount"]),
+                    text(&n["onlineMemberCount"]),
+                    text(&n["totalMemberCount"]),
+                ]
+            })
+            .collect(),
+    )
+}
+
+fn network_details(network: &Value) -> String {
+    let config = &network["config"];
+    let list = |value: &Value, f: fn(&Value) -> String| {
+        value
+            .as_array()
+            .map(|items| items.iter().map(f).collect::<Vec<_>>().join(", "))
+            .unwrap_or_default()
+    };
+    table(
+        &["FIELD", "VALUE"],
+        [
+            ("id", text(&network["id"])),
+            ("name", text(&config["name"])),
+            ("description", text(&network["description"])),
+            ("private", text(&config["private"])),
+            (
+                "ip pools",
+                list(&config["ipAssignmentPools"], |p| {
+                    format!("{}-{}", text(&p["ipRangeStart"]), text(&p["ipRangeEnd"]))
+                }),
+            ),
+            (
+                "routes",
+                list(&config["routes"], |r| match &r["via"] {
+                    Value::Null => text(&r["target"]),
+                    via => format!("{} via {}", text(&r["target"]), text(via)),
+                }),
+            ),
+            (
+                "members",
+                format!(
+                    "{} authorized, {} online, {} total",
+                    text(&network["authorizedMemberCount"]),
+                    text(&network["onlineMemberCount"]),
+                    text(&network["totalMemberCount"])
+                ),
+            ),
+        ]
+        .into_iter()
+        .map(|(field, value)| vec![field.to_string(), value])
+        .collect(),
+    )
+}
+
+fn member_table(members: &[Value]) -> String {
+    table(
+        &[
+            "ID",
+            "NAME",
+            "AUTHORIZED",
+            "ONLINE",
+            "IP ADDRESSES",
+            "VERSION",
+        ],
+        members
+            .iter()
+            .map(|m| {
+                vec![
+                    text(&m["nodeId"]),
+                    text(&m["name"]),
+                    text(&m["config"]["authorized"]),
+                    text(&m["online"]),
+                    m["config"]["ipAssignments"]
+                        .as_array()
+                        .map(|ips| ips.iter().map(text).collect::<Vec<_>>().join(","))
+                        .unwrap_or_default(),
+                    text(&m["clientVersion"]),
+                ]
+            })
+            .collect(),
+    )
+}
+
+fn peer_table(peers: &[Value]) -> String {
+    table(
+        &["ADDRESS", "ROLE", "VERSION", "LATENCY", "PATH"],
+        peers
+            .iter()
+            .map(|p| {
+                let path = p["paths"]
+                    .as_array()
+                    .and_then(|paths| {
+                        paths
+                            .iter()
+                            .find(|path| path["preferred"].as_bool() == Some(true))
+                            .or(paths.first())
+                    })
+                    .map(|path| text(&path["address"]))
+                    .unwrap_or_else(|| "relay".to_string());
+                vec![
+                    text(&p["address"]),
+                    text(&p["role"]),
+                    text(&p["version"]),
+                    text(&p["latency"]),
+                    path,
+                ]
+            })
+            .collect(),
+    )
+}
+
+/// A cell of a table, empty for null.
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Left aligned columns separated by two spaces.
+fn table(headers: &[&str], rows: Vec<Vec<String>>) -> String {
+    let mut widths = headers.iter().map(|h| h.len()).collect::<Vec<_>>();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let headers = headers.iter().map(|h| h.to_string()).collect();
+    let mut output = String::new();
+    for row in std::iter::once(headers).chain(rows) {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        output.push_str(line.trim_end());
+        output.push('\n');
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_table() {
+        let output = member_table(&[
+            json!({"nodeId": "abcdef0123", "name": "laptop", "online": true,
+                "config": {"authorized": true, "ipAssignments": ["10.0.0.1", "fd00::1"]}}),
+            json!({"nodeId": "0123456789", "config": {"authorized": false}}),
+        ]);
+        assert_eq!(
+            output,
+            "ID          NAME    AUTHORIZED  ONLINE  IP ADDRESSES      VERSION\n\
+             abcdef0123  laptop  true        true    10.0.0.1,fd00::1\n\
+             0123456789          false\n"
+        );
+    }
+}
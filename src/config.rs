@@ -0,0 +1,658 @@
+use std::{
+    collections::HashSet,
+    fs,
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use clap::{
+    error::ErrorKind, parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{cli::Command, log, server::Listen};
+
+/// Name of the config file looked up in the work dir.
+pub const CONFIG_FILE: &str = "zerotier-edge.toml";
+
+/// Shown instead of secrets by `--print-config`.
+const MASKED: &str = "********";
+
+/// Where the work dir was set.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum WorkDirSource {
+    Flag,
+    Env,
+    Config,
+    #[default]
+    Default,
+}
+
+/// Settings are taken from the command line, then the `ZT_EDGE_*` environment
+/// variables, then the config file, then the defaults.
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    #[serde(skip)]
+    pub command: Option<Command>,
+
+    /// print subcommand results as JSON instead of tables.
+    #[arg(long, global = true)]
+    #[serde(skip)]
+    pub json: bool,
+
+    /// config file, default: zerotier-edge.toml in the work dir.
+    #[arg(short = 'c', long, env = "ZT_EDGE_CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    /// print the effective configuration and exit.
+    #[arg(long)]
+    #[serde(skip)]
+    pub print_config: bool,
+
+    /// where the work dir was set, determined on load.
+    #[arg(skip)]
+    #[serde(skip)]
+    pub work_dir_source: WorkDirSource,
+
+    /// addresses to listen on instead of host and port: ip:port, [ipv6]:port,
+    /// unix:<path>, zt:<port> for the addresses managed by ZeroTier or
+    /// zt:<network id>:<port> for the ones of a single network.
+    #[arg(short = 'L', long, env = "ZT_EDGE_LISTEN", value_delimiter = ',')]
+    pub listen: Vec<String>,
+
+    /// permissions of unix sockets, in octal.
+    #[arg(long, env = "ZT_EDGE_UNIX_SOCKET_MODE", default_value = "660")]
+    pub unix_socket_mode: String,
+
+    /// specify hostname
+    #[arg(short = 'H', long, env = "ZT_EDGE_HOST")]
+    pub host: Option<String>,
+
+    /// specify port
+    #[arg(short = 'P', long, env = "ZT_EDGE_PORT", default_value_t = 9394)]
+    pub port: u16,
+
+    /// zerotier controller api address, or unix:<path> of a socket, default:
+    /// http://localhost with the port of zerotier-one.port in the zerotier home.
+    #[arg(short = 'Z', long, env = "ZT_EDGE_ZT_API")]
+    pub zt_api: Option<String>,
+
+    /// zerotier home directory, to discover the api port and authtoken.secret.
+    #[arg(long, env = "ZT_EDGE_ZT_HOME")]
+    pub zt_home: Option<PathBuf>,
+
+    /// work dir, the directry to store configurations.
+    #[arg(short = 'W', long, env = "ZT_EDGE_WORK_DIR")]
+    pub work_dir: Option<PathBuf>,
+
+    /// path prefix to serve under, e.g. /zerotier-edge behind a proxy.
+    #[arg(long, env = "ZT_EDGE_BASE_PATH", default_value = "/")]
+    pub base_path: String,
+
+    /// file containing the controller token for background tasks and the
+    /// subcommands, default: authtoken.secret in the zerotier home.
+    #[arg(long, env = "ZT_EDGE_TOKEN_FILE")]
+    pub token_file: Option<PathBuf>,
+
+    /// token clients may send instead of the controller token, in
+    /// `X-ZT1-AUTH` or `Authorization: Bearer`, to be served with the token file.
+    #[arg(long, env = "ZT_EDGE_AUTH_TOKEN", hide_env_values = true)]
+    pub auth_token: Option<String>,
+
+    /// serve requests without a token with the token file, any client reaching
+    /// the service gets controller access. Only loopback and unix sockets are
+    /// allowed to listen then.
+    #[arg(long, env = "ZT_EDGE_ALLOW_ANONYMOUS")]
+    pub allow_anonymous: bool,
+
+    /// certificate chain in PEM format, enables HTTPS together with `tls_key`.
+    #[arg(long, env = "ZT_EDGE_TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// private key in PEM format.
+    #[arg(long, env = "ZT_EDGE_TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// serve HTTPS with a self-signed certificate generated into the work dir,
+    /// when no certificate is given.
+    #[arg(long, env = "ZT_EDGE_TLS_SELF_SIGNED")]
+    pub tls_self_signed: bool,
+
+    /// port of a plain HTTP listener redirecting to HTTPS.
+    #[arg(long, env = "ZT_EDGE_HTTP_REDIRECT_PORT")]
+    pub http_redirect_port: Option<u16>,
+
+    /// seconds to wait for running requests and background tasks on shutdown.
+    #[arg(long, env = "ZT_EDGE_SHUTDOWN_TIMEOUT", default_value_t = 30)]
+    pub shutdown_timeout: u64,
+
+    /// log level: error, warn, info, debug or trace.
+    #[arg(long, env = "ZT_EDGE_LOG_LEVEL", default_value = "info")]
+    pub log_level: String,
+
+    /// timeout in seconds of a request to the controller.
+    #[arg(long, env = "ZT_EDGE_REQUEST_TIMEOUT", default_value_t = 10)]
+    pub request_timeout: u64,
+
+    /// timeout in seconds to connect to the controller.
+    #[arg(long, env = "ZT_EDGE_CONNECT_TIMEOUT", default_value_t = 3)]
+    pub connect_timeout: u64,
+
+    /// maximum number of concurrent requests to the controller.
+    #[arg(long, env = "ZT_EDGE_CONCURRENCY", default_value_t = 16)]
+    pub concurrency: usize,
+
+    /// retries of failed GET requests to the controller.
+    #[arg(long, env = "ZT_EDGE_RETRIES", default_value_t = 2)]
+    pub retries: u32,
+
+    /// seconds since the last packet from a member for it to be considered online.
+    #[arg(long, env = "ZT_EDGE_ONLINE_THRESHOLD", default_value_t = 300)]
+    pub online_threshold: u64,
+
+    /// seconds between refreshes of the cached controller state, 0 to disable caching.
+    #[arg(long, env = "ZT_EDGE_CACHE_INTERVAL", default_value_t = 30)]
+    pub cache_interval: u64,
+
+    /// seconds between samples of the member presence and peer history, 0 to disable sampling.
+    #[arg(long, env = "ZT_EDGE_SAMPLE_INTERVAL", default_value_t = 60)]
+    pub sample_interval: u64,
+
+    /// days to keep the member presence history.
+    #[arg(long, env = "ZT_EDGE_HISTORY_RETENTION", default_value_t = 30)]
+    pub history_retention: u64,
+
+    /// days to keep the peer latency history, sampled at every sample interval.
+    #[arg(long, env = "ZT_EDGE_PEER_HISTORY_RETENTION", default_value_t = 7)]
+    pub peer_history_retention: u64,
+
+    /// more controllers to manage, served under /api/v1/controllers/<name>:
+    /// <name>=<api>[,<token file>], or `[[controllers]]` tables in the config file.
+    #[arg(
+        long = "controller",
+        env = "ZT_EDGE_CONTROLLERS",
+        value_delimiter = ' ',
+        value_name = "NAME=API[,TOKEN_FILE]"
+    )]
+    pub controllers: Vec<ControllerSpec>,
+
+    /// controller to replicate to, a standby with the identity of the
+    /// `replicate_from` controller restored.
+    #[arg(long, env = "ZT_EDGE_REPLICATE_TO")]
+    pub replicate_to: Option<String>,
+
+    /// controller to replicate from.
+    #[arg(long, env = "ZT_EDGE_REPLICATE_FROM", default_value = DEFAULT_CONTROLLER)]
+    pub replicate_from: String,
+
+    /// seconds between replications.
+    #[arg(long, env = "ZT_EDGE_REPLICATION_INTERVAL", default_value_t = 300)]
+    pub replication_interval: u64,
+
+    /// only report the drift of the standby, without changing it.
+    #[arg(long, env = "ZT_EDGE_REPLICATION_DRY_RUN")]
+    pub replication_dry_run: bool,
+
+    /// delete the networks and members of the standby missing on the primary.
+    #[arg(long, env = "ZT_EDGE_REPLICATION_PRUNE")]
+    pub replication_prune: bool,
+}
+
+/// Name of the controller of `zt_api` and `token_file`.
+pub const DEFAULT_CONTROLLER: &str = "default";
+
+/// A controller besides the default one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ControllerSpec {
+    pub name: String,
+    /// api address, or unix:<path> of a socket.
+    pub zt_api: String,
+    /// file containing its token, for background tasks only.
+    pub token_file: Option<PathBuf>,
+}
+
+impl FromStr for ControllerSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, rest) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <name>=<api>[,<token file>], got `{}`", s))?;
+        let (zt_api, token_file) = match rest.split_once(',') {
+            Some((zt_api, token_file)) => (zt_api, Some(PathBuf::from(token_file))),
+            None => (rest, None),
+        };
+        Ok(Self {
+            name: name.to_string(),
+            zt_api: zt_api.to_string(),
+            token_file,
+        })
+    }
+}
+
+impl Args {
+    /// Parses the command line and merges in the config file, exits on errors.
+    pub fn load() -> Self {
+        let matches = Self::command().get_matches();
+        let args = Self::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+
+        // looked up at most once, it warns when falling back.
+        let mut default = None;
+        let mut default_dir = || default.get_or_insert_with(default_work_dir).clone();
+
+        let (path, required) = match (&args.config, &args.work_dir) {
+            (Some(path), _) => (path.clone(), true),
+            (None, Some(work_dir)) => (work_dir.join(CONFIG_FILE), false),
+            (None, None) => (default_dir().join(CONFIG_FILE), false),
+        };
+
+        let mut args = match read_config_file(&path, required) {
+            Ok(Some(file)) => args.merge_file(&matches, file),
+            Ok(None) => Ok(args),
+            Err(err) => Err(err),
+        }
+        .unwrap_or_else(|err| {
+            let err = format!("{}: {}", path.display(), err);
+            Self::command().error(ErrorKind::InvalidValue, err).exit()
+        });
+        args.work_dir_source = match matches.value_source("work_dir") {
+            Some(ValueSource::CommandLine) => WorkDirSource::Flag,
+            Some(ValueSource::EnvVariable) => WorkDirSource::Env,
+            _ if args.work_dir.is_some() => WorkDirSource::Config,
+            _ => WorkDirSource::Default,
+        };
+        let work_dir = args.work_dir.take().unwrap_or_else(default_dir);
+        args.work_dir = Some(fs::canonicalize(&work_dir).unwrap_or(work_dir));
+
+        let valid = args
+            .log_level()
+            .and(args.base_path())
+            .and(args.listen())
+            .and(args.unix_socket_mode())
+            .and(args.controllers())
+            .and(args.replication())
+            .and(args.auth_token());
+        if let Err(err) = valid {
+            Self::command().error(ErrorKind::InvalidValue, err).exit()
+        }
+        if args.tls_cert.is_some() != args.tls_key.is_some() {
+            let err = "tls_cert and tls_key must be given together";
+            Self::command()
+                .error(ErrorKind::MissingRequiredArgument, err)
+                .exit()
+        }
+
+        if args.print_config {
+            print!("{}", args.to_toml());
+            std::process::exit(0);
+        }
+
+        args
+    }
+
+    /// Takes the settings of the file that were not given on the command line
+    /// or in the environment.
+    fn merge_file(self, matches: &ArgMatches, file: toml::Table) -> Result<Self, String> {
+        let Value::Object(mut values) = serde_json::to_value(&self).map_err(|e| e.to_string())?
+        else {
+            unreachable!("args serialize to a map");
+        };
+
+        for (key, value) in file {
+            if !values.contains_key(&key) {
+                return Err(format!("unknown setting `{}`", key));
+            }
+            if matches!(
+                matches.value_source(&key),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            ) {
+                continue;
+            }
+            let value = serde_json::to_value(value).map_err(|e| e.to_string())?;
+            values.insert(key, value);
+        }
+
+        let args: Self =
+            serde_json::from_value(Value::Object(values)).map_err(|e| e.to_string())?;
+        Ok(Self {
+            command: self.command,
+            json: self.json,
+            config: self.config,
+            print_config: self.print_config,
+            ..args
+        })
+    }
+
+    /// The effective settings in the format of the config file.
+    fn to_toml(&self) -> String {
+        let args = Self {
+            work_dir: Some(self.work_dir()),
+            zt_api: Some(self.zt_api()),
+            zt_home: self.zt_home(),
+            auth_token: self.auth_token.as_ref().map(|_| MASKED.to_string()),
+            ..self.clone()
+        };
+        toml::to_string(&args).expect("args serialize to toml")
+    }
+
+    /// Resolved once by `load`, so the default is not looked up again.
+    pub fn work_dir(&self) -> PathBuf {
+        let work_dir = self.work_dir.clone().unwrap_or_else(default_work_dir);
+        fs::canonicalize(&work_dir).unwrap_or(work_dir)
+    }
+
+    /// Where the work dir comes from, and why the default is not the
+    /// zerotier home.
+    pub fn work_dir_source(&self) -> String {
+        match self.work_dir_source {
+            WorkDirSource::Flag => "set by --work-dir".to_string(),
+            WorkDirSource::Env => "set by ZT_EDGE_WORK_DIR".to_string(),
+            WorkDirSource::Config => "set in the config file".to_string(),
+            WorkDirSource::Default => match zerotier_home_unusable() {
+                None => "the zerotier home".to_string(),
+                Some(reason) => format!("the default, {}", reason),
+            },
+        }
+    }
+
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_cert.is_some() || self.tls_self_signed
+    }
+
+    /// The listen addresses, host and port when none are given.
+    pub fn listen(&self) -> Result<Vec<Listen>, String> {
+        if self.listen.is_empty() {
+            let host = self.host.as_deref().unwrap_or("localhost");
+            let addr = (host, self.port)
+                .to_socket_addrs()
+                .map_err(|e| format!("invalid hostname `{}`: {}", host, e))?
+                .next()
+                .ok_or_else(|| format!("`{}` has no address", host))?;
+            return Ok(vec![Listen::Tcp(addr)]);
+        }
+
+        let mut listens = vec![];
+        for listen in &self.listen {
+            listens.extend(Listen::parse(listen)?);
+        }
+        Ok(listens)
+    }
+
+    pub fn unix_socket_mode(&self) -> Result<u32, String> {
+        u32::from_str_radix(&self.unix_socket_mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o777)
+            .ok_or_else(|| format!("invalid unix socket mode `{}`", self.unix_socket_mode))
+    }
+
+    /// The base path without trailing slash, empty for the root.
+    pub fn base_path(&self) -> Result<String, String> {
+        let base_path = self.base_path.trim_matches('/');
+        let valid = |c: char| c.is_ascii_alphanumeric() || "-._~/".contains(c);
+        if !base_path.chars().all(valid) || base_path.contains("//") {
+            return Err(format!("invalid base path `{}`", self.base_path));
+        }
+        Ok(match base_path {
+            "" => String::new(),
+            base_path => format!("/{}", base_path),
+        })
+    }
+
+    pub fn log_level(&self) -> Result<tracing::Level, String> {
+        self.log_level
+            .parse()
+            .map_err(|_| format!("invalid log level `{}`", self.log_level))
+    }
+
+    pub fn zt_home(&self) -> Option<PathBuf> {
+        self.zt_home
+            .clone()
+            .or_else(|| zerotier_home().map(Path::to_path_buf))
+    }
+
+    pub fn zt_api(&self) -> String {
+        if let Some(zt_api) = &self.zt_api {
+            return zt_api.clone();
+        }
+        let port = self
+            .zt_home()
+            .and_then(|home| fs::read_to_string(home.join("zerotier-one.port")).ok())
+            .and_then(|port| port.trim().parse::<u16>().ok())
+            .unwrap_or(9993);
+        format!("http://localhost:{}", port)
+    }
+
+    /// Checks the names of the controllers: unique, and usable in urls and paths.
+    pub fn controllers(&self) -> Result<(), String> {
+        let mut names = HashSet::from([DEFAULT_CONTROLLER]);
+        for controller in &self.controllers {
+            let name = controller.name.as_str();
+            let valid = |c: char| c.is_ascii_alphanumeric() || "-_".contains(c);
+            if name.is_empty() || !name.chars().all(valid) {
+                return Err(format!("invalid controller name `{}`", name));
+            }
+            if !names.insert(name) {
+                return Err(format!("controller `{}` is given twice", name));
+            }
+            if controller.zt_api.is_empty() {
+                return Err(format!("controller `{}` has no api address", name));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn auth_token(&self) -> Result<(), String> {
+        match self.auth_token.as_deref() {
+            Some(token) if token.trim().len() < 16 => {
+                Err("auth_token must have at least 16 characters".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that the controllers to replicate from and to are known and differ.
+    pub fn replication(&self) -> Result<(), String> {
+        let Some(to) = self.replicate_to.as_deref() else {
+            return Ok(());
+        };
+        let known = |name: &str| {
+            name == DEFAULT_CONTROLLER || self.controllers.iter().any(|c| c.name == name)
+        };
+        for name in [self.replicate_from.as_str(), to] {
+            if !known(name) {
+                return Err(format!("unknown controller `{}` to replicate", name));
+            }
+        }
+        if self.replicate_from == to {
+            return Err(format!("controller `{}` cannot replicate to itself", to));
+        }
+        if self.replication_interval == 0 {
+            return Err("replication interval must not be 0".to_string());
+        }
+        Ok(())
+    }
+
+    /// The token file and whether it was configured, rather than discovered.
+    pub fn token_file(&self) -> Option<(PathBuf, bool)> {
+        match &self.token_file {
+            Some(path) => Some((path.clone(), true)),
+            None => Some((self.zt_home()?.join("authtoken.secret"), false)),
+        }
+    }
+}
+
+fn read_config_file(path: &Path, required: bool) -> Result<Option<toml::Table>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if !required && err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.to_string()),
+    };
+    text.parse()
+        .map(Some)
+        .map_err(|e: toml::de::Error| e.to_string())
+}
+
+/// The system wide home directory of zerotier-one.
+fn zerotier_home() -> Option<&'static Path> {
+    use cfg_if::cfg_if;
+
+    // # System https://docs.zerotier.com/zerotier/zerotier.conf#configuration-files
+    cfg_if! {
+        if #[cfg(target_os = "windows")] {
+            let working_directory = Some("C:\\ProgramData\\ZeroTier\\One");
+        } else if #[cfg(target_os = "macos")] {
+            let working_directory = Some("/Library/Application Support/ZeroTier/One");
+        } else if #[cfg(target_os = "linux")] {
+            let working_directory = Some("/var/lib/zerotier-one");
+        } else if #[cfg(any(target_os = "freebsd", target_os = "openbsd"))] {
+            let working_directory = Some("/var/db/zerotier-one");
+        } else {
+            let working_directory: Option<&'static str> = None;
+        }
+    };
+    working_directory.map(Path::new)
+}
+
+/// Why the zerotier home cannot be the work dir, `None` if it can.
+fn zerotier_home_unusable() -> Option<String> {
+    use faccess::PathExt;
+
+    let Some(working_directory) = zerotier_home() else {
+        return Some("no zerotier home on this platform".to_string());
+    };
+    if !working_directory.is_dir() {
+        Some(format!("{:?} does not exist", working_directory))
+    } else if !working_directory.writable() {
+        Some(format!("{:?} is not writable", working_directory))
+    } else {
+        None
+    }
+}
+
+fn default_work_dir() -> PathBuf {
+    use cfg_if::cfg_if;
+    // try to reuse ZeroTier working directory
+
+    if let Some(working_directory) = zerotier_home() {
+        match zerotier_home_unusable() {
+            None => return working_directory.to_path_buf(),
+            Some(reason) => log::warn!(
+                "{}, will try other directory as working_directory to store extra configuration.",
+                reason
+            ),
+        }
+    }
+
+    // # User https://docs.zerotier.com/zerotier/zerotier.conf#user
+
+    cfg_if! {
+        if #[cfg(target_os = "windows")] {
+            let working_directory = Some("AppData\\Local\\ZeroTier");
+        } else if #[cfg(target_os = "macos")] {
+            let working_directory = Some("Library/Application Support/ZeroTier");
+        } else {
+            let working_directory: Option<&'static str> = None;
+        }
+    }
+    if let (Some(home), Some(working_directory)) =
+        (dirs::home_dir(), working_directory.map(Path::new))
+    {
+        home.join(working_directory)
+    } else {
+        Path::new("zt").to_path_buf()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Args;
+    use clap::{CommandFactory, FromArgMatches};
+
+    #[test]
+    fn test_config_precedence() {
+        let matches = Args::command()
+            .try_get_matches_from(["zerotier-edge", "--port", "8000"])
+            .unwrap();
+        let args = Args::from_arg_matches(&matches).unwrap();
+
+        let file = r#"
+            port = 9000
+            zt_api = "http://10.0.0.1:9993"
+            cache_interval = 0
+        "#
+        .parse()
+        .unwrap();
+        let args = args.merge_file(&matches, file).unwrap();
+        assert_eq!(args.port, 8000);
+        assert_eq!(args.zt_api(), "http://10.0.0.1:9993");
+        assert_eq!(args.cache_interval, 0);
+        assert_eq!(args.retries, 2);
+
+        let args = Args::from_arg_matches(&matches).unwrap();
+        let file = r#"auth_token = "0123456789abcdef""#.parse().unwrap();
+        let args = args.merge_file(&matches, file).unwrap();
+        assert!(args.auth_token().is_ok());
+        assert!(args.to_toml().contains(r#"auth_token = "********""#));
+        assert!(!args.to_toml().contains("0123456789abcdef"));
+
+        let args = Args::from_arg_matches(&matches).unwrap();
+        let file = "prot = 9000".parse().unwrap();
+        assert!(args.merge_file(&matches, file).is_err());
+    }
+
+    #[test]
+    fn test_controllers() {
+        let matches = Args::command()
+            .try_get_matches_from([
+                "zerotier-edge",
+                "--controller",
+                "site-a=http://10.0.0.1:9993,/etc/zt/a.secret",
+            ])
+            .unwrap();
+        let args = Args::from_arg_matches(&matches).unwrap();
+        assert_eq!(args.controllers[0].name, "site-a");
+        assert_eq!(args.controllers[0].zt_api, "http://10.0.0.1:9993");
+        assert!(args.controllers().is_ok());
+
+        let file = r#"
+            [[controllers]]
+            name = "site-b"
+            zt_api = "unix:/run/zt-b.sock"
+        "#
+        .parse()
+        .unwrap();
+        let matches = Args::command()
+            .try_get_matches_from(["zerotier-edge"])
+            .unwrap();
+        let args = Args::from_arg_matches(&matches).unwrap();
+        let args = args.merge_file(&matches, file).unwrap();
+        assert_eq!(args.controllers[0].name, "site-b");
+        assert_eq!(args.controllers[0].token_file, None);
+
+        let matches = Args::command()
+            .try_get_matches_from(["zerotier-edge", "--controller", "a=http://x"])
+            .unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        assert!(args.replication().is_ok());
+        args.replicate_to = Some("a".to_string());
+        assert!(args.replication().is_ok());
+        args.replicate_to = Some("b".to_string());
+        assert!(args.replication().is_err());
+        args.replicate_to = Some("default".to_string());
+        assert!(args.replication().is_err());
+
+        let matches = Args::command()
+            .try_get_matches_from(["zerotier-edge", "--controller", "default=http://x"])
+            .unwrap();
+        assert!(Args::from_arg_matches(&matches)
+            .unwrap()
+            .controllers()
+            .is_err());
+    }
+}
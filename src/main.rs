@@ -1,161 +1,260 @@
 use axum::{
+    extract::OriginalUri,
     http::Uri,
-    response::{IntoResponse, Response},
+    response::{Html, IntoResponse, Redirect, Response},
     routing::get,
-    Router,
+    Extension, Router,
 };
-use clap::Parser;
-use std::{fs, net::ToSocketAddrs, path::Path};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::sync::{Mutex, Semaphore};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 mod api;
+mod cli;
+mod config;
 mod log;
+mod server;
+mod systemd;
 
-use api::ApiState;
-
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// specify hostname
-    #[arg(short = 'H', long)]
-    host: Option<String>,
-
-    /// specify port
-    #[arg(short = 'P', long, default_value_t = 9394)]
-    port: u16,
-
-    /// zerotier controller api address, default: http://localhost:9993
-    #[arg(short = 'Z', long)]
-    zt_api: Option<String>,
-
-    /// work dir, the directry to store configurations.
-    #[arg(short = 'W', long)]
-    work_dir: Option<std::path::PathBuf>,
-    //  /// print token to stdout, default: false.
-    //  #[arg(short = 'T', long)]
-    //  token: bool
-}
+use api::{ApiState, Cache, ControllerState, Replication, TokenFile};
+use config::{Args, DEFAULT_CONTROLLER};
+use server::Listen;
 
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
+    let args = Args::load();
 
     run(args).await
 }
 
 async fn run(args: Args) {
+    if args.command.is_some() {
+        // keep stdout for the output of the command.
+        tracing_subscriber::fmt()
+            .with_max_level(args.log_level().unwrap_or(tracing::Level::INFO))
+            .with_writer(std::io::stderr)
+            .init();
+
+        let state = new_state(&args);
+        std::process::exit(cli::run(&args, &state).await);
+    }
+
     // initialize tracing
     tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
+        .with_max_level(args.log_level().unwrap_or(tracing::Level::INFO))
         .init();
 
-    let addr = (args.host.as_deref().unwrap_or("localhost"), args.port)
-        .to_socket_addrs()
-        .expect("invalid hostname")
-        .next()
-        .expect("must have one sock addr.");
-
-    let zt_api = args
-        .zt_api
-        .unwrap_or_else(|| "http://localhost:9993".to_string());
-
-    let work_dir = args.work_dir.unwrap_or_else(|| {
-        use cfg_if::cfg_if;
-        use faccess::PathExt;
-        // try to reuse ZeroTier working directory
-
-        // # System https://docs.zerotier.com/zerotier/zerotier.conf#configuration-files
-        cfg_if! {
-            if #[cfg(target_os = "windows")] {
-                let working_directory = Some("C:\\ProgramData\\ZeroTier\\One");
-            } else if #[cfg(target_os = "macos")] {
-                let working_directory = Some("/Library/Application Support/ZeroTier/One");
-            } else if #[cfg(target_os = "linux")] {
-                let working_directory = Some("/var/lib/zerotier-one");
-            } else if #[cfg(any(target_os = "freebsd", target_os = "openbsd"))] {
-                let working_directory = Some("/var/db/zerotier-one");
-            } else {
-                let working_directory: Option<&'static str> = None;
-            }
-        };
+    let state = new_state(&args);
+    let work_dir = state.default_controller().work_dir.clone();
 
-        if let Some(working_directory) = working_directory.map(Path::new) {
-            if working_directory.exists()
-                && working_directory.is_dir()
-                && working_directory
-                    .writable()
-            {
-               return working_directory.to_path_buf();
-            } else {
-                log::warn!("{:?} is not writable, will try other directory as working_directory to store extra configuration.", working_directory);
-            }
-        }
+    log::info!("=>\tzerotier api: {}", args.zt_api());
+    for controller in &args.controllers {
+        log::info!("=>\tcontroller {}: {}", controller.name, controller.zt_api);
+    }
+    if let Some(to) = &args.replicate_to {
+        log::info!("=>\treplication: {} to {}", args.replicate_from, to);
+    }
+    log::info!("=>\tworking_directory: {:?}", &work_dir);
 
-        // # User https://docs.zerotier.com/zerotier/zerotier.conf#user
+    api::spawn_tasks(&state);
 
-        cfg_if! {
-            if #[cfg(target_os = "windows")] {
-                let working_directory = Some("AppData\\Local\\ZeroTier");
-            } else if #[cfg(target_os = "macos")] {
-                let working_directory = Some("Library/Application Support/ZeroTier");
-            } else {
-                let working_directory: Option<&'static str> = None;
-            }
-        }
-        if let (Some(home), Some(working_directory)) =
-            (dirs::home_dir(), working_directory.map(Path::new))
-        {
-            home.join(working_directory)
-        } else {
-            Path::new("zt").to_path_buf()
+    let listens = args.listen().unwrap_or_default();
+    let listens = match Listen::resolve(listens, &state).await {
+        Ok(listens) => listens,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
         }
-    });
-    let work_dir = fs::canonicalize(&work_dir).unwrap_or(work_dir);
-
-    log::info!("=>\tzerotier api: {}", zt_api);
-    log::info!("=>\tworking_directory: {:?}", &work_dir);
+    };
 
     // build our application with a route
+    let base_path = args.base_path().unwrap_or_default();
+
     let app = Router::new()
         .merge(api::routes())
         .route("/", get(index_handler))
-        .route("/*file", get(static_handler))
-        .with_state(
-            ApiState {
-                api: zt_api,
-                work_dir,
-                client: Default::default(),
-            }
-            .into(),
-        );
+        .route("/*file", get(static_handler));
 
-    if !addr.ip().is_loopback() {
-        log::warn!("For security reasons, it is recommended to use the loopback address and use nginx's https proxy for this service.");
+    let app = match base_path.as_str() {
+        "" => app,
+        // the nested `/` only matches the prefix without trailing slash.
+        base_path => Router::new()
+            .nest(base_path, app)
+            .route(&format!("{}/", base_path), get(index_handler)),
     }
+    .layer(Extension(BasePath(base_path.clone())))
+    .with_state(state.clone());
 
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+    let shutdown = state.shutdown.clone();
+    tokio::spawn(async move {
+        server::shutdown_signal().await;
+        log::info!("shutting down, waiting for running requests.");
+        systemd::notify_stopping();
+        shutdown.cancel();
+    });
+    systemd::spawn_watchdog(state.shutdown.clone());
 
-    log::info!("=>\tlistening on http://{}", addr);
+    server::serve(&args, &work_dir, listens, app, state.shutdown.clone()).await;
 
-    axum::serve(listener, app).await.unwrap();
+    if !api::wait_tasks(&state, Duration::from_secs(args.shutdown_timeout)).await {
+        log::warn!("background tasks still running, stopping anyway.");
+    }
+    log::info!("stopped.");
 }
 
+/// The state shared by the server and the subcommands, panics when a configured
+/// token file cannot be read.
+fn new_state(args: &Args) -> Arc<ApiState> {
+    let work_dir = args.work_dir();
+
+    let doctor = matches!(args.command, Some(cli::Command::Doctor));
+    let token = match args.token_file() {
+        // the doctor reports an unreadable token file instead.
+        Some((path, configured)) => load_token_file(path, configured && !doctor),
+        None => TokenFile::default(),
+    };
+    let mut controllers = vec![new_controller(
+        args,
+        DEFAULT_CONTROLLER,
+        args.zt_api(),
+        work_dir.clone(),
+        token,
+    )];
+
+    for spec in &args.controllers {
+        // only used by background tasks, requests bring their own token.
+        let token = match &spec.token_file {
+            Some(path) => load_token_file(path.clone(), true),
+            None => TokenFile::default(),
+        };
+        let work_dir = work_dir.join("controllers.d").join(&spec.name);
+        controllers.push(new_controller(
+            args,
+            &spec.name,
+            spec.zt_api.clone(),
+            work_dir,
+            token,
+        ));
+    }
+
+    let replication = args.replicate_to.as_deref().and_then(|to| {
+        let find = |name: &str| controllers.iter().find(|c| c.name == name).cloned();
+        Some(Replication::new(
+            find(&args.replicate_from)?,
+            find(to)?,
+            Duration::from_secs(args.replication_interval),
+            args.replication_dry_run,
+            args.replication_prune,
+        ))
+    });
+
+    Arc::new(ApiState {
+        controllers,
+        auth_token: args.auth_token.clone(),
+        allow_anonymous: args.allow_anonymous,
+        retries: args.retries,
+        online_threshold: Duration::from_secs(args.online_threshold),
+        sample_interval: Duration::from_secs(args.sample_interval),
+        history_retention: Duration::from_secs(args.history_retention * 24 * 60 * 60),
+        peer_history_retention: Duration::from_secs(args.peer_history_retention * 24 * 60 * 60),
+        replication,
+        shutdown: CancellationToken::new(),
+        tasks: TaskTracker::new(),
+    })
+}
+
+fn load_token_file(path: PathBuf, configured: bool) -> TokenFile {
+    TokenFile::load(path.clone(), configured)
+        .unwrap_or_else(|err| panic!("failed to read token file {:?}: {}", path, err))
+}
+
+fn new_controller(
+    args: &Args,
+    name: &str,
+    zt_api: String,
+    work_dir: PathBuf,
+    token: TokenFile,
+) -> Arc<ControllerState> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(args.request_timeout))
+        .connect_timeout(Duration::from_secs(args.connect_timeout));
+    let (zt_api, client) = match zt_api.strip_prefix("unix:") {
+        #[cfg(unix)]
+        Some(path) => ("http://localhost".to_string(), client.unix_socket(path)),
+        _ => (zt_api, client),
+    };
+
+    Arc::new(ControllerState {
+        name: name.to_string(),
+        api: zt_api,
+        work_dir,
+        token,
+        client: client.build().expect("failed to build http client"),
+        limiter: Semaphore::new(args.concurrency.max(1)),
+        cache: Cache::new(Duration::from_secs(args.cache_interval)),
+        update_lock: Mutex::new(()),
+    })
+}
+
+/// Path prefix all routes are nested under, empty for the root.
+#[derive(Debug, Clone)]
+struct BasePath(String);
+
 // We use static route matchers ("/" and "/index.html") to serve our home
 // page.
-async fn index_handler() -> impl IntoResponse {
-    static_handler("/index.html".parse::<Uri>().unwrap()).await
+async fn index_handler(base_path: Extension<BasePath>, OriginalUri(uri): OriginalUri) -> Response {
+    // relative urls of the web ui only resolve below the trailing slash.
+    if !uri.path().ends_with('/') {
+        return Redirect::permanent(&format!("{}/", uri.path())).into_response();
+    }
+    static_handler(base_path, "/index.html".parse::<Uri>().unwrap()).await
 }
 
 // We use a wildcard matcher ("/dist/*file") to match against everything
 // within our defined assets directory. This is the directory on our Asset
 // struct below, where folder = "examples/public/".
-async fn static_handler(uri: Uri) -> impl IntoResponse {
+async fn static_handler(Extension(BasePath(base_path)): Extension<BasePath>, uri: Uri) -> Response {
     let mut path = uri.path().trim_start_matches('/').to_string();
 
     if path.starts_with("dist/") {
         path = path.replace("dist/", "");
     }
 
-    StaticFile(path)
+    if path == "index.html" && !base_path.is_empty() {
+        if let Some(content) = Asset::get(&path) {
+            let html = String::from_utf8_lossy(&content.data);
+            return Html(rewrite_index(&html, &base_path)).into_response();
+        }
+    }
+
+    StaticFile(path).into_response()
+}
+
+/// Points the asset and api urls of `index.html` below `base_path`.
+fn rewrite_index(html: &str, base_path: &str) -> String {
+    let mut html = html.to_string();
+    for attr in [" src=\"/", " href=\"/"] {
+        let mut from = 0;
+        while let Some(i) = html[from..].find(attr).map(|i| i + from) {
+            let end = i + attr.len();
+            // keep protocol relative urls and the ones already rewritten.
+            let rewritten = html[end - 1..].starts_with(&format!("{}/", base_path));
+            if !html[end..].starts_with('/') && !rewritten {
+                html.insert_str(end - 1, base_path);
+            }
+            from = end;
+        }
+    }
+
+    if !html.contains("<base ") {
+        if let Some(i) = html
+            .find("<head")
+            .and_then(|i| html[i..].find('>').map(|j| i + j + 1))
+        {
+            html.insert_str(i, &format!("<base href=\"{}/\" />", base_path));
+        }
+    }
+    html
 }
 
 #[derive(rust_embed::RustEmbed)]
@@ -181,3 +280,20 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::rewrite_index;
+
+    #[test]
+    fn test_rewrite_index() {
+        let html = r#"<html><head lang="en"><script src="/assets/index.js"></script>
+<link href="./assets/index.css"><link href="//cdn.example.com/a.css"></head></html>"#;
+        let html = rewrite_index(html, "/zt");
+        assert!(html.contains(r#"<head lang="en"><base href="/zt/" />"#));
+        assert!(html.contains(r#"src="/zt/assets/index.js""#));
+        assert!(html.contains(r#"href="./assets/index.css""#));
+        assert!(html.contains(r#"href="//cdn.example.com/a.css""#));
+        assert_eq!(rewrite_index(&html, "/zt"), html);
+    }
+}
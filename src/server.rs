@@ -0,0 +1,513 @@
+use std::{
+    fs,
+    future::Future,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    http::{header::HOST, uri::Authority, HeaderMap, StatusCode, Uri},
+    response::Redirect,
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    api::{self, ApiError, ApiState},
+    config::Args,
+    log, systemd,
+};
+
+/// How often the certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// An address to listen on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    /// the addresses assigned to this node in a ZeroTier network, in all
+    /// joined networks if `network_id` is none.
+    ZeroTier {
+        network_id: Option<String>,
+        port: u16,
+    },
+}
+
+impl Listen {
+    /// Parses a listen address, host names resolve to all their addresses.
+    pub fn parse(s: &str) -> Result<Vec<Self>, String> {
+        let invalid = || format!("invalid listen address `{}`", s);
+
+        if let Some(path) = s.strip_prefix("unix:") {
+            if cfg!(not(unix)) {
+                return Err(format!("unix sockets are not supported: `{}`", s));
+            }
+            return Ok(vec![Self::Unix(PathBuf::from(path))]);
+        }
+
+        if let Some(rest) = s.strip_prefix("zt:") {
+            let (network_id, port) = match rest.split_once(':') {
+                Some((network_id, port))
+                    if network_id.len() == 16
+                        && network_id.chars().all(|c| c.is_ascii_hexdigit()) =>
+                {
+                    (Some(network_id.to_string()), port)
+                }
+                Some(_) => return Err(invalid()),
+                None => (None, rest),
+            };
+            let port = port.parse().map_err(|_| invalid())?;
+            return Ok(vec![Self::ZeroTier { network_id, port }]);
+        }
+
+        let addrs = s.to_socket_addrs().map_err(|_| invalid())?;
+        Ok(addrs.map(Self::Tcp).collect())
+    }
+
+    /// Replaces the ZeroTier listen addresses by the ones currently assigned.
+    pub async fn resolve(listens: Vec<Self>, state: &Arc<ApiState>) -> Result<Vec<Self>, String> {
+        let mut resolved = vec![];
+        for listen in listens {
+            let Self::ZeroTier { network_id, port } = listen else {
+                resolved.push(listen);
+                continue;
+            };
+            let ips = match api::managed_addresses(state, network_id.as_deref()).await {
+                Ok(ips) => ips,
+                Err(ApiError::Unauthorized) => {
+                    return Err(
+                        "a token is needed to look up ZeroTier addresses, see token_file"
+                            .to_string(),
+                    )
+                }
+                Err(err) => return Err(format!("failed to look up ZeroTier addresses: {}", err)),
+            };
+            if ips.is_empty() {
+                return Err(format!(
+                    "no ZeroTier address assigned in {}",
+                    network_id.as_deref().unwrap_or("any network")
+                ));
+            }
+            resolved.extend(
+                ips.into_iter()
+                    .map(|ip| Self::Tcp(SocketAddr::new(ip, port))),
+            );
+        }
+        Ok(resolved)
+    }
+}
+
+type Server = Pin<Box<dyn Future<Output = io::Result<()>> + Send>>;
+
+/// A bound socket, either configured or passed by the service manager.
+enum Socket {
+    Tcp(std::net::TcpListener),
+    /// the path is removed on shutdown for sockets bound by us.
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener, Option<PathBuf>),
+}
+
+/// Serves on the sockets of systemd socket activation, or else on `listens`,
+/// until `shutdown` is cancelled and the connections are drained.
+pub async fn serve(
+    args: &Args,
+    work_dir: &Path,
+    listens: Vec<Listen>,
+    app: Router,
+    shutdown: CancellationToken,
+) {
+    let tls = match args.tls_enabled() {
+        true => Some(tls_config(args, work_dir).await),
+        false => None,
+    };
+
+    let sockets = match inherited_sockets() {
+        Ok(sockets) if !sockets.is_empty() => Ok(sockets),
+        Ok(_) => bind(args, listens),
+        Err(err) => Err(err),
+    }
+    .unwrap_or_else(|err| panic!("failed to listen: {}", err));
+
+    let mut servers: Vec<Server> = vec![];
+    let mut unix_paths = vec![];
+    for socket in sockets {
+        let server = match socket {
+            Socket::Tcp(listener) => {
+                serve_tcp(args, listener, tls.clone(), app.clone(), shutdown.clone()).await
+            }
+            #[cfg(unix)]
+            Socket::Unix(listener, path) => {
+                unix_paths.extend(path);
+                serve_unix(listener, app.clone(), shutdown.clone())
+            }
+        };
+        servers.push(server.unwrap_or_else(|err| panic!("failed to listen: {}", err)));
+    }
+
+    systemd::notify_ready();
+
+    let timeout = Duration::from_secs(args.shutdown_timeout);
+    tokio::select! {
+        result = futures::future::try_join_all(servers) => {
+            result.unwrap();
+        }
+        _ = async {
+            shutdown.cancelled().await;
+            tokio::time::sleep(timeout).await
+        } => log::warn!("requests still running after {}s, stopping anyway.", timeout.as_secs()),
+    }
+
+    for path in unix_paths {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Resolves on SIGINT or SIGTERM.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install the ctrl-c handler")
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        signal(SignalKind::terminate())
+            .expect("failed to install the SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => (),
+        _ = terminate => (),
+    }
+}
+
+/// The listening sockets passed by systemd, see `systemd.socket(5)`.
+fn inherited_sockets() -> io::Result<Vec<Socket>> {
+    let mut fds = listenfd::ListenFd::from_env();
+    let mut sockets = vec![];
+    for i in 0..fds.len() {
+        if let Ok(Some(listener)) = fds.take_tcp_listener(i) {
+            sockets.push(Socket::Tcp(listener));
+            continue;
+        }
+        #[cfg(unix)]
+        if let Some(listener) = fds.take_unix_listener(i)? {
+            sockets.push(Socket::Unix(listener, None));
+            continue;
+        }
+        return Err(io::Error::other(format!("unsupported socket {} passed", i)));
+    }
+    if !sockets.is_empty() {
+        log::info!("=>\tusing {} sockets of socket activation", sockets.len());
+    }
+    Ok(sockets)
+}
+
+fn bind(args: &Args, listens: Vec<Listen>) -> io::Result<Vec<Socket>> {
+    listens
+        .into_iter()
+        .map(|listen| match listen {
+            Listen::Tcp(addr) => std::net::TcpListener::bind(addr)
+                .map(Socket::Tcp)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", addr, e))),
+            #[cfg(unix)]
+            Listen::Unix(path) => {
+                let mode = args.unix_socket_mode().map_err(io::Error::other)?;
+                bind_unix(&path, mode)
+                    .map(|listener| Socket::Unix(listener, Some(path.clone())))
+                    .map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", path, e)))
+            }
+            listen => Err(io::Error::other(format!("cannot listen on {:?}", listen))),
+        })
+        .collect()
+}
+
+/// Refuses anonymous access on addresses other clients can reach.
+fn check_exposed(args: &Args, addr: SocketAddr, tls: bool) -> io::Result<()> {
+    if addr.ip().is_loopback() {
+        return Ok(());
+    }
+    if args.allow_anonymous {
+        return Err(io::Error::other(format!(
+            "{}: anonymous access is only allowed on loopback addresses and unix sockets",
+            addr
+        )));
+    }
+    if !tls {
+        log::warn!("For security reasons, it is recommended to use the loopback address, enable TLS or use nginx's https proxy for this service.");
+    }
+    Ok(())
+}
+
+async fn serve_tcp(
+    args: &Args,
+    listener: std::net::TcpListener,
+    tls: Option<RustlsConfig>,
+    app: Router,
+    shutdown: CancellationToken,
+) -> io::Result<Server> {
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+    check_exposed(args, addr, tls.is_some())?;
+
+    let Some(config) = tls else {
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        log::info!("=>\tlistening on http://{}", addr);
+        return Ok(Box::pin(async move {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await
+        }));
+    };
+
+    if let Some(port) = args.http_redirect_port {
+        spawn_redirect(
+            SocketAddr::new(addr.ip(), port),
+            addr.port(),
+            shutdown.clone(),
+        )
+        .await;
+    }
+
+    let handle = axum_server::Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            shutdown.cancelled().await;
+            handle.graceful_shutdown(None);
+        }
+    });
+
+    log::info!("=>\tlistening on https://{}", addr);
+    Ok(Box::pin(
+        axum_server::from_tcp_rustls(listener, config)
+            .handle(handle)
+            .serve(app.into_make_service()),
+    ))
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: u32) -> io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    // left over by a previous run.
+    if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Serves plain HTTP on a unix socket, e.g. for a local reverse proxy.
+#[cfg(unix)]
+fn serve_unix(
+    listener: std::os::unix::net::UnixListener,
+    app: Router,
+    shutdown: CancellationToken,
+) -> io::Result<Server> {
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::{conn::auto, graceful::GracefulShutdown},
+        service::TowerToHyperService,
+    };
+
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::UnixListener::from_std(listener)?;
+    let addr = listener.local_addr()?;
+    match addr.as_pathname() {
+        Some(path) => log::info!("=>\tlistening on unix:{}", path.display()),
+        None => log::info!("=>\tlistening on an unnamed unix socket"),
+    }
+
+    Ok(Box::pin(async move {
+        let graceful = GracefulShutdown::new();
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => accepted?.0,
+                _ = shutdown.cancelled() => break,
+            };
+            let service = TowerToHyperService::new(app.clone());
+            let connection = auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .into_owned();
+            let connection = graceful.watch(connection);
+            tokio::spawn(async move {
+                if let Err(err) = connection.await {
+                    log::debug!("unix socket connection failed: {}", err);
+                }
+            });
+        }
+        graceful.shutdown().await;
+        Ok(())
+    }))
+}
+
+async fn tls_config(args: &Args, work_dir: &Path) -> RustlsConfig {
+    let (cert, key) = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+        _ => self_signed(work_dir, args.host.as_deref())
+            .unwrap_or_else(|err| panic!("failed to generate certificate: {}", err)),
+    };
+
+    // any other installed provider is fine as well.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let config = RustlsConfig::from_pem_file(&cert, &key)
+        .await
+        .unwrap_or_else(|err| panic!("failed to load certificate {:?}: {}", cert, err));
+    spawn_reload(config.clone(), cert, key);
+    config
+}
+
+/// Certificate and key in `work_dir/tls`, generated on the first run.
+fn self_signed(work_dir: &Path, host: Option<&str>) -> Result<(PathBuf, PathBuf), String> {
+    let dir = work_dir.join("tls");
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    if cert_path.exists() && key_path.exists() {
+        return Ok((cert_path, key_path));
+    }
+
+    let mut names = vec![
+        "localhost".to_string(),
+        "127.0.0.1".to_string(),
+        "::1".to_string(),
+    ];
+    if let Some(host) = host.filter(|h| !names.iter().any(|n| n == h)) {
+        names.push(host.to_string());
+    }
+    let certified = rcgen::generate_simple_self_signed(names).map_err(|e| e.to_string())?;
+
+    let write = || -> io::Result<()> {
+        fs::create_dir_all(&dir)?;
+        write_private(&key_path, certified.key_pair.serialize_pem().as_bytes())?;
+        fs::write(&cert_path, certified.cert.pem())
+    };
+    write().map_err(|e| format!("{:?}: {}", dir, e))?;
+
+    log::info!("generated a self-signed certificate {:?}", cert_path);
+    Ok((cert_path, key_path))
+}
+
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    use io::Write;
+    let mut options = fs::File::options();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
+
+/// Reloads the certificate whenever one of the files is modified.
+fn spawn_reload(config: RustlsConfig, cert: PathBuf, key: PathBuf) {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    tokio::spawn(async move {
+        let mut loaded = (modified(&cert), modified(&key));
+        loop {
+            tokio::time::sleep(RELOAD_INTERVAL).await;
+            let current = (modified(&cert), modified(&key));
+            if current == loaded {
+                continue;
+            }
+            match config.reload_from_pem_file(&cert, &key).await {
+                Ok(()) => log::info!("reloaded certificate {:?}", cert),
+                // e.g. only one of the files is written yet, retry next time.
+                Err(err) => {
+                    log::warn!("failed to reload certificate {:?}: {}", cert, err);
+                    continue;
+                }
+            }
+            loaded = current;
+        }
+    });
+}
+
+/// Redirects plain HTTP requests on `addr` to HTTPS on `https_port`.
+async fn spawn_redirect(addr: SocketAddr, https_port: u16, shutdown: CancellationToken) {
+    let listener = match tokio::net::TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::warn!("failed to listen on {} for redirects: {}", addr, err);
+            return;
+        }
+    };
+    let app = Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move {
+        redirect(&headers, &uri, https_port).ok_or(StatusCode::BAD_REQUEST)
+    });
+
+    log::info!("=>\tredirecting http://{} to https", addr);
+    tokio::spawn(async move {
+        let server = axum::serve(listener, app).with_graceful_shutdown(shutdown.cancelled_owned());
+        if let Err(err) = server.await {
+            log::warn!("redirect listener failed: {}", err);
+        }
+    });
+}
+
+fn redirect(headers: &HeaderMap, uri: &Uri, https_port: u16) -> Option<Redirect> {
+    let host = headers
+        .get(HOST)?
+        .to_str()
+        .ok()?
+        .parse::<Authority>()
+        .ok()?;
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    let url = match https_port {
+        443 => format!("https://{}{}", host.host(), path),
+        port => format!("https://{}:{}{}", host.host(), port, path),
+    };
+    Some(Redirect::permanent(&url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Listen;
+
+    #[test]
+    fn test_parse_listen() {
+        assert_eq!(
+            Listen::parse("127.0.0.1:80").unwrap(),
+            [Listen::Tcp("127.0.0.1:80".parse().unwrap())]
+        );
+        assert_eq!(
+            Listen::parse("[::]:80").unwrap(),
+            [Listen::Tcp("[::]:80".parse().unwrap())]
+        );
+        assert_eq!(
+            Listen::parse("unix:/run/edge.sock").unwrap(),
+            [Listen::Unix("/run/edge.sock".into())]
+        );
+        assert_eq!(
+            Listen::parse("zt:80").unwrap(),
+            [Listen::ZeroTier {
+                network_id: None,
+                port: 80
+            }]
+        );
+        assert_eq!(
+            Listen::parse("zt:abcdef0123000001:80").unwrap(),
+            [Listen::ZeroTier {
+                network_id: Some("abcdef0123000001".to_string()),
+                port: 80
+            }]
+        );
+        assert!(Listen::parse("zt:abc:80").is_err());
+        assert!(Listen::parse("zt:zzzzzzzzzzzzzzzz:80").is_err());
+        assert!(Listen::parse("127.0.0.1").is_err());
+    }
+}
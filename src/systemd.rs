@@ -0,0 +1,45 @@
+//! Notifications to systemd, they do nothing when not started by it.
+
+use tokio_util::sync::CancellationToken;
+
+#[cfg(target_os = "linux")]
+fn notify(state: &[sd_notify::NotifyState]) {
+    if let Err(err) = sd_notify::notify(false, state) {
+        crate::log::debug!("failed to notify systemd: {}", err);
+    }
+}
+
+/// All sockets are bound and requests are served.
+pub fn notify_ready() {
+    #[cfg(target_os = "linux")]
+    notify(&[sd_notify::NotifyState::Ready]);
+}
+
+pub fn notify_stopping() {
+    #[cfg(target_os = "linux")]
+    notify(&[sd_notify::NotifyState::Stopping]);
+}
+
+/// Pings the watchdog at half its interval while the runtime is responsive.
+pub fn spawn_watchdog(shutdown: CancellationToken) {
+    #[cfg(target_os = "linux")]
+    {
+        let mut usec = 0;
+        if !sd_notify::watchdog_enabled(false, &mut usec) {
+            return;
+        }
+        let interval = std::time::Duration::from_micros(usec) / 2;
+
+        tokio::spawn(async move {
+            loop {
+                notify(&[sd_notify::NotifyState::Watchdog]);
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => (),
+                    _ = shutdown.cancelled() => break,
+                }
+            }
+        });
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = shutdown;
+}
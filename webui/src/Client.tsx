@@ -68,7 +68,7 @@ export default (props: { children: JSX.Element }) => {
   const members = () => members0;
   const currentNetwork = () => currentNetwork0.currentNetwork;
   const authRequired = () => !loading() && !status();
-  const baseUrl = `${location.pathname}api/v1`;
+  const baseUrl = new URL('api/v1', document.baseURI).pathname;
 
 
   let client = createClient<paths>({